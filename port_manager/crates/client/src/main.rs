@@ -1,15 +1,32 @@
+mod output;
+mod run;
+mod watch;
+
 use clap::{Parser, Subcommand};
-use common::{AllocateRequest, AllocateResponse, HeartbeatRequest, ReleaseRequest, Lease, LookupResponse};
-use reqwest::Client;
-use std::process::{Command, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use common::{
+    AllocateRequest, AllocationStrategy, ApiError, CreateTokenRequest, ErrorCode, TokenScope, HistoryQuery, ImportMode,
+    PoolSpec, ReserveRequest, StateExport,
+};
+use chrono::{DateTime, Utc};
+use output::{CliError, Context, Deleted, OutputFormat, Released};
+use portmanager::{ClientBuilder, PortManagerClient};
+use run::{RestartPolicy, RunOptions};
+use std::io::Read;
+use std::path::PathBuf;
 use std::time::Duration;
 use tokio::time;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Daemon URL, e.g. http://192.168.1.10:3030 [env: PORTMANAGER_URL]
+    #[arg(long, global = true)]
+    url: Option<String>,
+
+    /// Output format; json, yaml and env use the API's field names
+    #[arg(long, short, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -21,18 +38,109 @@ enum Commands {
         service_name: String,
         #[arg(long)]
         ttl: Option<u64>,
+        /// Preferred port, used if it is free
+        #[arg(long)]
+        port: Option<u16>,
+        /// Release the lease as soon as this process exits
+        #[arg(long)]
+        owner_pid: Option<u32>,
+        /// Number of ports to allocate as one group
+        #[arg(long)]
+        count: Option<u16>,
+        /// Require the ports of a group to be consecutive
+        #[arg(long)]
+        contiguous: bool,
+        /// Named port within the group, e.g. --role http --role grpc
+        #[arg(long = "role")]
+        roles: Vec<String>,
+        /// Pool to allocate from
+        #[arg(long)]
+        pool: Option<String>,
     },
-    /// Release an allocated port
+    /// Release an allocated port, or every port of a group
     Release {
-        port: u16,
+        #[arg(required_unless_present = "group")]
+        port: Option<u16>,
+        /// Group id returned by a multi-port allocation
+        #[arg(long, conflicts_with = "port")]
+        group: Option<String>,
+    },
+    /// List active leases in your namespace, or in all namespaces for admins
+    List {
+        /// Only show leases from this pool
+        #[arg(long)]
+        pool: Option<String>,
+        /// Only show leases from this namespace
+        #[arg(long)]
+        namespace: Option<String>,
+    },
+    /// Manage named port pools
+    Pool {
+        #[command(subcommand)]
+        command: PoolCommands,
+    },
+    /// Manage permanent port reservations
+    Reservation {
+        #[command(subcommand)]
+        command: ReservationCommands,
+    },
+    /// Manage API tokens (requires admin rights)
+    Token {
+        #[command(subcommand)]
+        command: TokenCommands,
+    },
+    /// List processes listening in the managed range without a lease
+    Conflicts {
+        /// Rescan now instead of showing the daemon's latest periodic scan
+        #[arg(long)]
+        refresh: bool,
     },
-    /// List all active leases
-    List,
     /// Allocate a port and send heartbeats in a loop
     Loop {
         service_name: String,
         #[arg(long)]
         ttl: Option<u64>,
+        /// Preferred port, used if it is free
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Live view of all leases; release or extend them from the keyboard
+    Watch {
+        /// Seconds between refreshes while the event stream is unavailable
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    /// Show what happened to leases: allocations, releases, expiries and late heartbeats
+    History {
+        /// Only entries for this service
+        #[arg(long)]
+        service: Option<String>,
+        /// Only entries for this port
+        #[arg(long)]
+        port: Option<u16>,
+        /// Only entries from this namespace
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Only entries since a time (RFC 3339) or for a recent span, e.g. 30m, 2h, 7d
+        #[arg(long, value_parser = parse_since)]
+        since: Option<DateTime<Utc>>,
+        /// Maximum number of entries
+        #[arg(long, default_value_t = 50)]
+        limit: u32,
+    },
+    /// Write leases, reservations and sticky ports as JSON (or YAML with -o yaml)
+    Export {
+        /// File to write instead of stdout
+        #[arg(long, short)]
+        file: Option<PathBuf>,
+    },
+    /// Restore state written by `export`
+    Import {
+        /// Exported JSON or YAML file, or - for stdin
+        file: PathBuf,
+        /// merge keeps existing state; replace drops it first
+        #[arg(long, default_value = "merge")]
+        mode: ImportMode,
     },
     /// Lookup a service by name
     Lookup {
@@ -47,220 +155,335 @@ enum Commands {
         #[arg(long)]
         ttl: Option<u64>,
 
+        /// Preferred port, used if it is free
+        #[arg(long)]
+        port: Option<u16>,
+
         /// Environment variable name for the port (default: PORT)
         #[arg(long, default_value = "PORT")]
         env_name: String,
 
+        /// Allocate a named port, exported as <ENV_NAME>_<ROLE>. <ENV_NAME> is set to the first one
+        #[arg(long = "role")]
+        roles: Vec<String>,
+
+        /// Require the role ports to be consecutive
+        #[arg(long)]
+        contiguous: bool,
+
+        /// Pool to allocate from
+        #[arg(long)]
+        pool: Option<String>,
+
+        /// Start the command again when it exits: never, on-failure or always. Waits 1s, then up to 30s after repeated crashes
+        #[arg(long, default_value = "never")]
+        restart: RestartPolicy,
+
+        /// Restart the command when files under this path change; may be repeated
+        #[arg(long = "watch", value_name = "PATH")]
+        watch: Vec<PathBuf>,
+
         /// Command and arguments to execute
         #[arg(last = true, required = true)]
         command: Vec<String>,
     },
 }
 
-const BASE_URL: &str = "http://localhost:3030";
+#[derive(Subcommand)]
+enum PoolCommands {
+    /// List all pools
+    List,
+    /// Create or update a pool
+    Set {
+        name: String,
+        /// Lowest port in the pool
+        #[arg(long)]
+        min: u16,
+        /// Highest port in the pool
+        #[arg(long)]
+        max: u16,
+        /// Default lease TTL in seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+        /// Allocation strategy: first_free, last_free or random
+        #[arg(long, default_value = "first_free")]
+        strategy: AllocationStrategy,
+    },
+    /// Delete a pool without active leases
+    Delete {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReservationCommands {
+    /// List all reservations
+    List,
+    /// Reserve a port permanently for a service
+    Add {
+        port: u16,
+        service_name: String,
+    },
+    /// Remove a reservation
+    Remove {
+        port: u16,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokenCommands {
+    /// List all tokens
+    List,
+    /// Create a token and print its secret, which is shown only once
+    Create {
+        name: String,
+        /// read, allocate or admin
+        #[arg(long, default_value = "allocate")]
+        scope: TokenScope,
+        /// Namespace the token acts in (default: yours)
+        #[arg(long)]
+        namespace: Option<String>,
+    },
+    /// Revoke a token by id
+    Revoke {
+        id: String,
+    },
+}
+
+/// Client for the daemon at `--url`, else wherever the SDK's
+/// `ClientBuilder::from_env` finds it: `PORTMANAGER_URL`, `url` in
+/// `~/.portmanager/client.toml`, the local Unix socket, or `http://localhost:3030`.
+/// `PORTMANAGER_TOKEN`, if set, is sent as the API token.
+fn connect(url: Option<String>) -> portmanager::Result<PortManagerClient> {
+    let mut builder = ClientBuilder::from_env()?;
+    if let Some(url) = url {
+        builder = builder.url(url);
+    }
+    builder.build()
+}
+
+/// Parse `--since`: either an RFC 3339 timestamp or a span like 90s, 30m, 2h or 7d before now.
+fn parse_since(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Ok(at.with_timezone(&Utc));
+    }
+    let invalid = || format!("invalid time or span: {}", value);
+    let unit = value.chars().last().ok_or_else(invalid)?;
+    let amount: i64 = value[..value.len() - unit.len_utf8()].parse().map_err(|_| invalid())?;
+    let span = match unit {
+        's' => chrono::Duration::seconds(amount),
+        'm' => chrono::Duration::minutes(amount),
+        'h' => chrono::Duration::hours(amount),
+        'd' => chrono::Duration::days(amount),
+        _ => return Err(invalid()),
+    };
+    Ok(Utc::now() - span)
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
     let cli = Cli::parse();
-    let client = Client::new();
+    let format = cli.output;
+    let result = match connect(cli.url) {
+        Ok(client) => execute(cli.command, &client, format).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        e.report(format);
+        std::process::exit(e.exit_code());
+    }
+}
 
-    match cli.command {
-        Commands::Alloc { service_name, ttl } => {
+async fn execute(command: Commands, client: &PortManagerClient, format: OutputFormat) -> Result<(), CliError> {
+    match command {
+        Commands::Alloc { service_name, ttl, port, owner_pid, count, contiguous, roles, pool } => {
             let req = AllocateRequest {
                 service_name,
                 ttl_seconds: ttl,
                 tags: None,
+                preferred_port: port,
+                owner_pid,
+                owner_start_time: None,
+                count,
+                contiguous,
+                roles: (!roles.is_empty()).then_some(roles),
+                pool,
             };
-            let resp = client.post(format!("{}/alloc", BASE_URL))
-                .json(&req)
-                .send()
-                .await?;
-
-            if resp.status().is_success() {
-                let alloc_resp: AllocateResponse = resp.json().await?;
-                println!("Allocated port: {}", alloc_resp.port);
-                println!("Lease: {:?}", alloc_resp.lease);
-            } else {
-                eprintln!("Failed to allocate port: {}", resp.status());
-            }
+            let alloc_resp = client.allocate(&req).await.context("Failed to allocate port")?;
+            format.print(&alloc_resp)?;
         }
-        Commands::Release { port } => {
-            let req = ReleaseRequest { port };
-            let resp = client.post(format!("{}/release", BASE_URL))
-                .json(&req)
-                .send()
-                .await?;
-
-            if resp.status().is_success() {
-                println!("Released port: {}", port);
-            } else {
-                eprintln!("Failed to release port: {}", resp.status());
-            }
+        Commands::Release { port, group } => {
+            let released = match (&group, port) {
+                (Some(group), _) => client.release_group(group).await.context(&format!("Failed to release {}", group)),
+                (None, Some(port)) => client.release(port).await.context(&format!("Failed to release {}", port)),
+                (None, None) => Err(CliError::Usage("Give a port or --group".to_string())),
+            };
+            released?;
+            format.print(&Released { port, group_id: group })?;
         }
-        Commands::List => {
-            let resp = client.get(format!("{}/list", BASE_URL))
-                .send()
-                .await?;
-
-            if resp.status().is_success() {
-                let leases: Vec<Lease> = resp.json().await?;
-                println!("Active Leases:");
-                for lease in leases {
-                    println!("Port: {}, Service: {}, TTL: {}s", lease.port, lease.service_name, lease.ttl_seconds);
+        Commands::List { pool, namespace } => {
+            let leases = client
+                .list_filtered(pool.as_deref(), namespace.as_deref())
+                .await
+                .context("Failed to list leases")?;
+            format.print(&leases)?;
+        }
+        Commands::Pool { command } => match command {
+            PoolCommands::List => {
+                let pools = client.pools().await.context("Failed to list pools")?;
+                format.print(&pools)?;
+            }
+            PoolCommands::Set { name, min, max, ttl, strategy } => {
+                let spec = PoolSpec { min_port: min, max_port: max, default_ttl: ttl, strategy };
+                let pool = client.save_pool(&name, &spec).await.context("Failed to save pool")?;
+                format.print(&pool)?;
+            }
+            PoolCommands::Delete { name } => {
+                client.delete_pool(&name).await.context("Failed to delete pool")?;
+                format.print(&Deleted { deleted: "pool", id: name })?;
+            }
+        },
+        Commands::Token { command } => match command {
+            TokenCommands::List => {
+                let tokens = client.tokens().await.context("Failed to list tokens")?;
+                format.print(&tokens)?;
+            }
+            TokenCommands::Create { name, scope, namespace } => {
+                let req = CreateTokenRequest { name, scope, namespace };
+                let created = client.create_token(&req).await.context("Failed to create token")?;
+                if format == OutputFormat::Table {
+                    eprintln!(
+                        "Created {} token '{}' ({}) for namespace '{}'. Store the secret now; it is not shown again:",
+                        created.token.scope.as_str(), created.token.name, created.token.id, created.token.namespace
+                    );
                 }
-            } else {
-                eprintln!("Failed to list leases: {}", resp.status());
+                format.print(&created)?;
             }
+            TokenCommands::Revoke { id } => {
+                client.revoke_token(&id).await.context("Failed to revoke token")?;
+                format.print(&Deleted { deleted: "token", id })?;
+            }
+        },
+        Commands::Conflicts { refresh } => {
+            let conflicts = client.conflicts(refresh).await.context("Failed to list conflicts")?;
+            format.print(&conflicts)?;
         }
-        Commands::Loop { service_name, ttl } => {
+        Commands::Reservation { command } => match command {
+            ReservationCommands::List => {
+                let reservations = client.reservations().await.context("Failed to list reservations")?;
+                format.print(&reservations)?;
+            }
+            ReservationCommands::Add { port, service_name } => {
+                let req = ReserveRequest { port, service_name };
+                let reservation = client.reserve(&req).await.context("Failed to reserve port")?;
+                format.print(&reservation)?;
+            }
+            ReservationCommands::Remove { port } => {
+                client.unreserve(port).await.context("Failed to remove reservation")?;
+                format.print(&Deleted { deleted: "reservation", id: port.to_string() })?;
+            }
+        },
+        Commands::Loop { service_name, ttl, port: preferred_port } => {
             let req = AllocateRequest {
                 service_name: service_name.clone(),
                 ttl_seconds: ttl,
                 tags: None,
+                preferred_port,
+                owner_pid: Some(std::process::id()),
+                owner_start_time: None,
+                ..Default::default()
             };
-            let resp = client.post(format!("{}/alloc", BASE_URL))
-                .json(&req)
-                .send()
-                .await?;
-
-            if resp.status().is_success() {
-                let alloc_resp: AllocateResponse = resp.json().await?;
-                let port = alloc_resp.port;
-                println!("Allocated port: {}. Starting heartbeat loop...", port);
+            let alloc_resp = client.allocate(&req).await.context("Failed to allocate port")?;
+            format.print(&alloc_resp)?;
+            let port = alloc_resp.port;
 
-                let mut interval = time::interval(Duration::from_secs(5));
-                loop {
-                    interval.tick().await;
-                    let hb_req = HeartbeatRequest { port };
-                    match client.post(format!("{}/heartbeat", BASE_URL)).json(&hb_req).send().await {
-                        Ok(r) if r.status().is_success() => println!("Heartbeat sent for {}", port),
-                        Ok(r) => {
-                            eprintln!("Heartbeat failed: {}", r.status());
-                            break;
-                        }
-                        Err(e) => {
-                            eprintln!("Heartbeat error: {}", e);
-                            break;
-                        }
-                    }
+            // Progress is for people; other formats print the allocation and nothing else
+            let verbose = format == OutputFormat::Table;
+            if verbose {
+                println!("Starting heartbeat loop...");
+            }
+            let mut interval = time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                client.heartbeat(port).await.context("Heartbeat failed")?;
+                if verbose {
+                    println!("Heartbeat sent for {}", port);
                 }
-            } else {
-                eprintln!("Failed to allocate port: {}", resp.status());
             }
         }
-        Commands::Lookup { service_name } => {
-            let resp = client.get(format!("{}/lookup?service={}", BASE_URL, service_name))
-                .send()
-                .await?;
-
-            if resp.status().is_success() {
-                let lookup: LookupResponse = resp.json().await?;
-                if let Some(port) = lookup.port {
-                    println!("{}", port);
-                } else {
-                    eprintln!("No port found for service: {}", service_name);
-                    std::process::exit(1);
+        Commands::Watch { interval } => {
+            if format != OutputFormat::Table {
+                return Err(CliError::Usage("watch is interactive and only supports --output table".to_string()));
+            }
+            watch::run(client.clone(), Duration::from_secs(interval.max(1))).await?;
+        }
+        Commands::History { service, port, namespace, since, limit } => {
+            let query = HistoryQuery { service, port, namespace, since, limit: Some(limit) };
+            let history = client.history(&query).await.context("Failed to load history")?;
+            format.print(&history)?;
+        }
+        Commands::Export { file } => {
+            let export = client.export().await.context("Failed to export state")?;
+            match file {
+                Some(path) => {
+                    std::fs::write(&path, format.render(&export)? + "\n")
+                        .map_err(|e| CliError::Local(format!("Failed to write {}: {}", path.display(), e)))?;
+                    eprintln!(
+                        "Exported {} lease(s), {} reservation(s) and {} sticky port(s) to {}",
+                        export.leases.len(),
+                        export.reservations.len(),
+                        export.sticky_ports.len(),
+                        path.display()
+                    );
                 }
+                None => format.print(&export)?,
+            }
+        }
+        Commands::Import { file, mode } => {
+            let mut text = String::new();
+            if file.as_os_str() == "-" {
+                std::io::stdin().read_to_string(&mut text)?;
             } else {
-                eprintln!("Failed to lookup service: {}", resp.status());
-                std::process::exit(1);
+                text = std::fs::read_to_string(&file)
+                    .map_err(|e| CliError::Local(format!("Failed to read {}: {}", file.display(), e)))?;
             }
+            // Exports are JSON by default; `export -o yaml` writes YAML
+            let export: StateExport = serde_json::from_str(&text)
+                .or_else(|e| serde_yaml::from_str(&text).map_err(|_| e))
+                .map_err(|e| CliError::Local(format!("Invalid export: {}", e)))?;
+
+            let report = client.import(&export, mode).await.context("Failed to import state")?;
+            format.print(&report)?;
+        }
+        Commands::Lookup { service_name } => {
+            let lookup = client.lookup(&service_name).await.context("Failed to lookup service")?;
+            if lookup.port.is_none() {
+                let message = format!("No port found for service: {}", service_name);
+                return Err(ApiError::new(ErrorCode::NotFound, message).into());
+            }
+            format.print(&lookup)?;
         }
-        Commands::Run { service_name, ttl, env_name, command } => {
+        Commands::Run { service_name, ttl, port: preferred_port, env_name, roles, contiguous, pool, restart, watch, command } => {
             if command.is_empty() {
-                eprintln!("No command specified");
-                std::process::exit(1);
+                return Err(CliError::Usage("No command specified".to_string()));
             }
 
-            // Allocate port
             let req = AllocateRequest {
-                service_name: service_name.clone(),
+                service_name,
                 ttl_seconds: ttl,
                 tags: None,
+                preferred_port,
+                owner_pid: Some(std::process::id()),
+                owner_start_time: None,
+                contiguous,
+                roles: (!roles.is_empty()).then_some(roles),
+                pool,
+                ..Default::default()
             };
-            let resp = client.post(format!("{}/alloc", BASE_URL))
-                .json(&req)
-                .send()
-                .await?;
-
-            if !resp.status().is_success() {
-                eprintln!("Failed to allocate port: {}", resp.status());
-                std::process::exit(1);
-            }
-
-            let alloc_resp: AllocateResponse = resp.json().await?;
-            let port = alloc_resp.port;
-            println!("Allocated port {} for service '{}'", port, service_name);
-
-            // Flag to signal heartbeat thread to stop
-            let running = Arc::new(AtomicBool::new(true));
-            let running_clone = running.clone();
-
-            // Spawn heartbeat task
-            let heartbeat_client = client.clone();
-            let heartbeat_handle = tokio::spawn(async move {
-                let mut interval = time::interval(Duration::from_secs(5));
-                while running_clone.load(Ordering::SeqCst) {
-                    interval.tick().await;
-                    if !running_clone.load(Ordering::SeqCst) {
-                        break;
-                    }
-                    let hb_req = HeartbeatRequest { port };
-                    match heartbeat_client.post(format!("{}/heartbeat", BASE_URL))
-                        .json(&hb_req)
-                        .send()
-                        .await
-                    {
-                        Ok(r) if r.status().is_success() => {}
-                        Ok(r) => {
-                            eprintln!("Heartbeat failed: {}", r.status());
-                            break;
-                        }
-                        Err(e) => {
-                            eprintln!("Heartbeat error: {}", e);
-                            break;
-                        }
-                    }
-                }
-            });
-
-            // Run the command with PORT environment variable
-            let cmd = &command[0];
-            let args = &command[1..];
-
-            println!("Running: {} {:?} with {}={}", cmd, args, env_name, port);
-
-            let status = Command::new(cmd)
-                .args(args)
-                .env(&env_name, port.to_string())
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .status();
-
-            // Stop heartbeat
-            running.store(false, Ordering::SeqCst);
-            heartbeat_handle.abort();
-
-            // Release port
-            let rel_req = ReleaseRequest { port };
-            let _ = client.post(format!("{}/release", BASE_URL))
-                .json(&rel_req)
-                .send()
-                .await;
-            println!("Released port {}", port);
-
-            // Exit with the command's exit code
-            match status {
-                Ok(s) => {
-                    if !s.success() {
-                        std::process::exit(s.code().unwrap_or(1));
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Failed to run command: {}", e);
-                    std::process::exit(1);
-                }
+            let options = RunOptions { command, env_name, restart, watch };
+            let status = run::run(client, format, req, options).await?;
+            if !status.success() {
+                run::exit_like(status);
             }
         }
     }
@@ -0,0 +1,587 @@
+//! Output formats and exit codes shared by all commands.
+//!
+//! `json` and `yaml` print the API's own field names, so scripts can rely on
+//! them; `env` flattens the same fields into `KEY=value` lines for `eval`.
+
+use clap::ValueEnum;
+use common::{
+    AllocateResponse, ApiError, ApiToken, Conflict, CreatedToken, ErrorCode, HistoryEntry, ImportReport, Lease,
+    LookupResponse, Pool, Reservation, StateExport,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt;
+
+/// Something else went wrong, e.g. a file could not be read.
+pub const EXIT_FAILURE: i32 = 1;
+/// Invalid arguments; clap uses the same code.
+pub const EXIT_USAGE: i32 = 2;
+/// The port, group, pool, reservation, token or service does not exist.
+pub const EXIT_NOT_FOUND: i32 = 3;
+/// No free port left in the pool, or the namespace's quota is used up.
+pub const EXIT_EXHAUSTED: i32 = 4;
+/// The daemon could not be reached.
+pub const EXIT_UNREACHABLE: i32 = 5;
+/// Missing, invalid or insufficient API token.
+pub const EXIT_DENIED: i32 = 6;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns and messages for people
+    #[default]
+    Table,
+    Json,
+    Yaml,
+    /// KEY=value lines, e.g. for `eval "$(portctl alloc my-api -o env)"`
+    Env,
+}
+
+impl OutputFormat {
+    pub fn render<T: Render>(self, value: &T) -> Result<String, CliError> {
+        Ok(match self {
+            OutputFormat::Table => value.table(),
+            OutputFormat::Json => serde_json::to_string_pretty(value)?,
+            OutputFormat::Yaml => serde_yaml::to_string(value)
+                .map_err(|e| CliError::Local(e.to_string()))?
+                .trim_end()
+                .to_string(),
+            OutputFormat::Env => {
+                let mut lines = Vec::new();
+                flatten(value.env_prefix(), &serde_json::to_value(value)?, &mut lines);
+                lines.join("\n")
+            }
+        })
+    }
+
+    /// Print `value` on stdout.
+    pub fn print<T: Render>(self, value: &T) -> Result<(), CliError> {
+        let text = self.render(value)?;
+        if !text.is_empty() {
+            println!("{}", text);
+        }
+        Ok(())
+    }
+}
+
+/// A command's result. JSON, YAML and env output come from its `Serialize` impl.
+pub trait Render: Serialize {
+    /// Human-readable form, without a trailing newline.
+    fn table(&self) -> String;
+
+    /// Prefix of the env variables; results that are lists need one.
+    fn env_prefix(&self) -> &str {
+        ""
+    }
+}
+
+/// Append `value` as `KEY=value` lines. Nested keys are joined with `_`, list
+/// items are numbered from 0 and every list also gets a `_COUNT`.
+fn flatten(key: &str, value: &Value, lines: &mut Vec<String>) {
+    let join = |child: &str| {
+        let child = child.to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        if key.is_empty() { child } else { format!("{}_{}", key, child) }
+    };
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                flatten(&join(name), field, lines);
+            }
+        }
+        Value::Array(items) => {
+            lines.push(format!("{}={}", join("count"), items.len()));
+            for (index, item) in items.iter().enumerate() {
+                flatten(&join(&index.to_string()), item, lines);
+            }
+        }
+        Value::Null => lines.push(format!("{}=", key)),
+        Value::String(s) => lines.push(format!("{}={}", key, shell_quote(s))),
+        other => lines.push(format!("{}={}", key, other)),
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-.,:/@%+=".contains(c);
+    if !s.is_empty() && s.chars().all(plain) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+/// Left-aligned columns under `headers`, two spaces apart.
+fn columns(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let headers = headers.iter().map(|h| h.to_string()).collect();
+    std::iter::once(headers)
+        .chain(rows)
+        .map(|row| {
+            let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, &w)| format!("{:<w$}", cell)).collect();
+            cells.join("  ").trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Name of an enum value as it appears in JSON, e.g. `in_use`.
+fn name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(Value::String(s)) => s,
+        _ => String::new(),
+    }
+}
+
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+impl Render for AllocateResponse {
+    fn table(&self) -> String {
+        let mut lines = Vec::new();
+        match &self.group_id {
+            Some(group_id) => {
+                lines.push(format!("Allocated group: {}", group_id));
+                for lease in &self.leases {
+                    lines.push(format!("  {}: {}", lease.role.as_deref().unwrap_or("-"), lease.port));
+                }
+            }
+            None => lines.push(format!(
+                "Allocated port: {} (service {}, pool {}, TTL {}s)",
+                self.port, self.lease.service_name, self.lease.pool, self.lease.ttl_seconds
+            )),
+        }
+        for skipped in &self.skipped {
+            lines.push(format!(
+                "Skipped port {}: {} {}",
+                skipped.port,
+                name(&skipped.reason),
+                skipped.detail.as_deref().unwrap_or("")
+            ).trim_end().to_string());
+        }
+        lines.join("\n")
+    }
+}
+
+impl Render for Vec<Lease> {
+    fn table(&self) -> String {
+        let rows = self
+            .iter()
+            .map(|l| {
+                vec![
+                    l.port.to_string(),
+                    l.service_name.clone(),
+                    or_dash(l.role.as_ref()),
+                    l.pool.clone(),
+                    l.namespace.clone(),
+                    format!("{}s", l.ttl_seconds),
+                    or_dash(l.owner_pid),
+                ]
+            })
+            .collect();
+        columns(&["PORT", "SERVICE", "ROLE", "POOL", "NAMESPACE", "TTL", "PID"], rows)
+    }
+
+    fn env_prefix(&self) -> &str {
+        "LEASES"
+    }
+}
+
+impl Render for Vec<Pool> {
+    fn table(&self) -> String {
+        let rows = self
+            .iter()
+            .map(|p| {
+                vec![
+                    p.name.clone(),
+                    format!("{}-{}", p.min_port, p.max_port),
+                    format!("{}s", p.default_ttl),
+                    p.strategy.as_str().to_string(),
+                ]
+            })
+            .collect();
+        columns(&["NAME", "RANGE", "TTL", "STRATEGY"], rows)
+    }
+
+    fn env_prefix(&self) -> &str {
+        "POOLS"
+    }
+}
+
+impl Render for Pool {
+    fn table(&self) -> String {
+        format!("Saved pool: {} ({}-{})", self.name, self.min_port, self.max_port)
+    }
+
+    fn env_prefix(&self) -> &str {
+        "POOL"
+    }
+}
+
+impl Render for Vec<Reservation> {
+    fn table(&self) -> String {
+        let rows = self
+            .iter()
+            .map(|r| vec![r.port.to_string(), r.service_name.clone(), r.reserved_at.format("%Y-%m-%d %H:%M").to_string()])
+            .collect();
+        columns(&["PORT", "SERVICE", "RESERVED"], rows)
+    }
+
+    fn env_prefix(&self) -> &str {
+        "RESERVATIONS"
+    }
+}
+
+impl Render for Reservation {
+    fn table(&self) -> String {
+        format!("Reserved port {} for service '{}'", self.port, self.service_name)
+    }
+
+    fn env_prefix(&self) -> &str {
+        "RESERVATION"
+    }
+}
+
+impl Render for Vec<ApiToken> {
+    fn table(&self) -> String {
+        let rows = self
+            .iter()
+            .map(|t| {
+                vec![
+                    t.id.clone(),
+                    t.name.clone(),
+                    t.scope.as_str().to_string(),
+                    t.namespace.clone(),
+                    t.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                ]
+            })
+            .collect();
+        columns(&["ID", "NAME", "SCOPE", "NAMESPACE", "CREATED"], rows)
+    }
+
+    fn env_prefix(&self) -> &str {
+        "TOKENS"
+    }
+}
+
+impl Render for CreatedToken {
+    /// Only the secret, so it can be captured; the command explains it on stderr.
+    fn table(&self) -> String {
+        self.secret.clone()
+    }
+}
+
+impl Render for Vec<Conflict> {
+    fn table(&self) -> String {
+        let rows = self
+            .iter()
+            .map(|c| vec![c.port.to_string(), or_dash(c.pid), c.command.clone().unwrap_or_else(|| "-".to_string())])
+            .collect();
+        columns(&["PORT", "PID", "COMMAND"], rows)
+    }
+
+    fn env_prefix(&self) -> &str {
+        "CONFLICTS"
+    }
+}
+
+impl Render for Vec<HistoryEntry> {
+    /// Oldest first, like a log; the API returns the newest first.
+    fn table(&self) -> String {
+        let rows = self
+            .iter()
+            .rev()
+            .map(|e| {
+                vec![
+                    e.at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    e.kind.as_str().to_string(),
+                    e.port.to_string(),
+                    e.service_name.clone(),
+                    e.namespace.clone(),
+                    e.reason.clone().unwrap_or_default(),
+                ]
+            })
+            .collect();
+        columns(&["TIME", "KIND", "PORT", "SERVICE", "NAMESPACE", "REASON"], rows)
+    }
+
+    fn env_prefix(&self) -> &str {
+        "HISTORY"
+    }
+}
+
+impl Render for StateExport {
+    /// The export is a document; people get it as JSON, which `import` reads back.
+    fn table(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+impl Render for ImportReport {
+    fn table(&self) -> String {
+        let mut lines = vec![format!(
+            "Imported {} lease(s), {} reservation(s) and {} sticky port(s) ({})",
+            self.leases, self.reservations, self.sticky_ports, self.mode.as_str()
+        )];
+        for conflict in &self.conflicts {
+            lines.push(format!(
+                "Skipped {} {} for '{}': {}",
+                name(&conflict.item),
+                conflict.port,
+                conflict.service_name,
+                conflict.reason
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+impl Render for LookupResponse {
+    /// Just the port, for `$(portctl lookup my-api)`.
+    fn table(&self) -> String {
+        or_dash(self.port)
+    }
+}
+
+/// Result of `release`: the port or the group that was released.
+#[derive(Serialize)]
+pub struct Released {
+    pub port: Option<u16>,
+    pub group_id: Option<String>,
+}
+
+impl Render for Released {
+    fn table(&self) -> String {
+        match (&self.group_id, self.port) {
+            (Some(group_id), _) => format!("Released group: {}", group_id),
+            (None, port) => format!("Released: {}", or_dash(port)),
+        }
+    }
+}
+
+/// Result of commands that delete something, e.g. `{"deleted": "pool", "id": "db"}`.
+#[derive(Serialize)]
+pub struct Deleted {
+    pub deleted: &'static str,
+    pub id: String,
+}
+
+impl Render for Deleted {
+    fn table(&self) -> String {
+        format!("Deleted {} {}", self.deleted, self.id)
+    }
+}
+
+/// Why a command failed; decides the exit code.
+#[derive(Debug)]
+pub enum CliError {
+    /// The daemon rejected the request.
+    Api(ApiError),
+    /// The daemon could not be reached.
+    Unreachable(String),
+    /// The arguments do not make sense together.
+    Usage(String),
+    /// Anything else, e.g. an unreadable file.
+    Local(String),
+}
+
+impl CliError {
+    /// Say what failed, e.g. "Failed to allocate port: pool 'db' not found".
+    pub fn context(self, context: &str) -> Self {
+        match self {
+            CliError::Api(mut e) => {
+                e.message = format!("{}: {}", context, e.message);
+                CliError::Api(e)
+            }
+            CliError::Local(message) => CliError::Local(format!("{}: {}", context, message)),
+            other => other,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Api(e) => match e.code {
+                ErrorCode::NotFound | ErrorCode::PoolNotFound | ErrorCode::PortNotLeased | ErrorCode::GroupNotFound => {
+                    EXIT_NOT_FOUND
+                }
+                ErrorCode::PoolExhausted | ErrorCode::QuotaExceeded => EXIT_EXHAUSTED,
+                ErrorCode::Unauthorized | ErrorCode::Forbidden => EXIT_DENIED,
+                _ => EXIT_FAILURE,
+            },
+            CliError::Unreachable(_) => EXIT_UNREACHABLE,
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::Local(_) => EXIT_FAILURE,
+        }
+    }
+
+    /// Print the error on stderr: as an `ApiError`-shaped object for `json` and
+    /// `yaml`, as text otherwise.
+    pub fn report(&self, format: OutputFormat) {
+        let error = match self {
+            CliError::Api(e) => serde_json::to_value(e).unwrap_or(Value::Null),
+            CliError::Unreachable(message) => json!({ "code": "unreachable", "message": message }),
+            CliError::Usage(message) => json!({ "code": "usage", "message": message }),
+            CliError::Local(message) => json!({ "code": "failed", "message": message }),
+        };
+        match format {
+            OutputFormat::Json => eprintln!("{}", serde_json::to_string_pretty(&error).unwrap_or_default()),
+            OutputFormat::Yaml => eprint!("{}", serde_yaml::to_string(&error).unwrap_or_default()),
+            OutputFormat::Table | OutputFormat::Env => {
+                eprintln!("{}", self);
+                // Explain why an exhausted pool had nothing to offer
+                let skipped = error.pointer("/details/skipped").and_then(|s| s.as_array());
+                for skipped in skipped.into_iter().flatten() {
+                    eprintln!(
+                        "  port {} skipped: {} {}",
+                        skipped["port"],
+                        skipped["reason"].as_str().unwrap_or(""),
+                        skipped["detail"].as_str().unwrap_or("")
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Say what failed when an SDK call does, e.g. `client.pools().await.context("Failed to list pools")`.
+pub trait Context<T> {
+    fn context(self, context: &str) -> Result<T, CliError>;
+}
+
+impl<T> Context<T> for portmanager::Result<T> {
+    fn context(self, context: &str) -> Result<T, CliError> {
+        self.map_err(|e| CliError::from(e).context(context))
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Api(e) => write!(f, "{}", e),
+            CliError::Unreachable(message) | CliError::Usage(message) | CliError::Local(message) => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+impl From<ApiError> for CliError {
+    fn from(error: ApiError) -> Self {
+        CliError::Api(error)
+    }
+}
+
+impl From<reqwest::Error> for CliError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_connect() || error.is_timeout() || error.is_request() {
+            CliError::Unreachable(format!("cannot reach the daemon: {}", error))
+        } else {
+            CliError::Local(error.to_string())
+        }
+    }
+}
+
+impl From<portmanager::Error> for CliError {
+    fn from(error: portmanager::Error) -> Self {
+        match error {
+            portmanager::Error::Api(e) => CliError::Api(e),
+            portmanager::Error::Http(e) => e.into(),
+            other => CliError::Local(other.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(error: std::io::Error) -> Self {
+        CliError::Local(error.to_string())
+    }
+}
+
+impl From<serde_json::Error> for CliError {
+    fn from(error: serde_json::Error) -> Self {
+        CliError::Local(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn env(prefix: &str, value: Value) -> Vec<String> {
+        let mut lines = Vec::new();
+        flatten(prefix, &value, &mut lines);
+        lines
+    }
+
+    #[test]
+    fn nested_fields_and_lists_become_upper_case_keys() {
+        let value = json!({
+            "port": 8000,
+            "lease": { "service_name": "my-api", "owner_pid": null, "tags": ["a", "b"] },
+            "group-id": "g 1",
+        });
+        assert_eq!(
+            env("", value),
+            [
+                "GROUP_ID='g 1'",
+                "LEASE_OWNER_PID=",
+                "LEASE_SERVICE_NAME=my-api",
+                "LEASE_TAGS_COUNT=2",
+                "LEASE_TAGS_0=a",
+                "LEASE_TAGS_1=b",
+                "PORT=8000",
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_are_numbered_under_their_prefix() {
+        let value = json!([{ "port": 8000 }, { "port": 8001 }]);
+        assert_eq!(env("LEASES", value), ["LEASES_COUNT=2", "LEASES_0_PORT=8000", "LEASES_1_PORT=8001"]);
+        assert_eq!(env("LEASES", json!([])), ["LEASES_COUNT=0"]);
+    }
+
+    #[test]
+    fn only_plain_words_are_left_unquoted() {
+        assert_eq!(shell_quote("my-api"), "my-api");
+        assert_eq!(shell_quote("http://localhost:3030/v1"), "http://localhost:3030/v1");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn quoted_values_survive_eval_in_sh() {
+        let values = ["it's", "$HOME and `id` and $(id)", "two\nlines\n", "'", "''\\'", "*", "a;b|c&d", "", "tab\there"];
+        for value in values {
+            let line = env("VALUE", json!(value)).remove(0);
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(format!("{}\nprintf %s \"$VALUE\"", line))
+                .output()
+                .expect("sh runs");
+            assert!(output.status.success(), "{:?}", line);
+            assert_eq!(String::from_utf8_lossy(&output.stdout), value, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn exit_codes_follow_the_kind_of_failure() {
+        let api = |code| CliError::Api(ApiError::new(code, "")).exit_code();
+        assert_eq!(CliError::Local(String::new()).exit_code(), 1);
+        assert_eq!(api(ErrorCode::Internal), 1);
+        assert_eq!(CliError::Usage(String::new()).exit_code(), 2);
+        for code in [ErrorCode::NotFound, ErrorCode::PoolNotFound, ErrorCode::PortNotLeased, ErrorCode::GroupNotFound] {
+            assert_eq!(api(code), 3);
+        }
+        assert_eq!(api(ErrorCode::PoolExhausted), 4);
+        assert_eq!(api(ErrorCode::QuotaExceeded), 4);
+        assert_eq!(CliError::Unreachable(String::new()).exit_code(), 5);
+        assert_eq!(api(ErrorCode::Unauthorized), 6);
+        assert_eq!(api(ErrorCode::Forbidden), 6);
+    }
+}
@@ -0,0 +1,478 @@
+//! `portctl run`: hold a lease for as long as a command runs.
+//!
+//! The command can be restarted when it exits (`--restart`) or when files
+//! change (`--watch`); it keeps the same lease, and so the same ports, throughout.
+//! With `--watch`, a command that exits without being restarted is started
+//! again on the next change, as long as portctl is not told to stop.
+
+use crate::output::{CliError, Context, OutputFormat};
+use clap::ValueEnum;
+use common::{AllocateRequest, AllocateResponse, ErrorCode};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use portmanager::PortManagerClient;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+
+/// Wait before the first restart; doubles with every crash in a row.
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// A command that ran at least this long before exiting is restarted quickly again.
+const STABLE_RUN: Duration = Duration::from_secs(10);
+/// Quiet period after a file change, since editors and builds touch several files at once.
+const WATCH_SETTLE: Duration = Duration::from_millis(300);
+/// How long the command gets to stop after SIGTERM before a file-change restart kills it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Shortest time between heartbeats, however short the lease's TTL.
+const HEARTBEAT_MIN: Duration = Duration::from_secs(1);
+
+/// When to start the command again after it exits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum RestartPolicy {
+    /// Exit along with the command
+    #[default]
+    Never,
+    /// Restart when it fails or is killed
+    OnFailure,
+    /// Restart whenever it exits
+    Always,
+}
+
+impl RestartPolicy {
+    fn restarts(self, status: ExitStatus) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !status.success(),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// The command `portctl run` supervises, and how.
+pub struct RunOptions {
+    pub command: Vec<String>,
+    pub env_name: String,
+    pub restart: RestartPolicy,
+    /// Restart the command when files under these paths change.
+    pub watch: Vec<PathBuf>,
+}
+
+/// Allocate, run the command with the ports in its environment and release the
+/// lease once it is done, whether it succeeds, fails, cannot be started or is
+/// stopped by a signal sent to portctl.
+pub async fn run(
+    client: &PortManagerClient,
+    format: OutputFormat,
+    req: AllocateRequest,
+    options: RunOptions,
+) -> Result<ExitStatus, CliError> {
+    let RunOptions { command, env_name, restart, watch } = options;
+    // Before allocating, so a mistyped path costs nothing
+    let mut changes = if watch.is_empty() { None } else { Some(Changes::watch(&watch)?) };
+    let mut signals = Signals::listen()?;
+
+    let service_name = req.service_name.clone();
+    let alloc_resp = client.allocate(&req).await.context("Failed to allocate port")?;
+    let port = alloc_resp.port;
+
+    // Stdout belongs to the command, so everything we say goes to stderr
+    let verbose = format == OutputFormat::Table;
+    if verbose {
+        eprintln!("Allocated port {} for service '{}'", port, service_name);
+    } else {
+        eprintln!("{}", format.render(&alloc_resp)?);
+    }
+
+    // Role ports are exported alongside the main one, e.g. PORT_GRPC
+    let mut env_vars = vec![(env_name.clone(), port)];
+    for lease in &alloc_resp.leases {
+        if let Some(role) = &lease.role {
+            let var = format!("{}_{}", env_name, role.to_uppercase().replace('-', "_"));
+            if verbose {
+                eprintln!("Allocated port {} for role '{}' ({})", lease.port, role, var);
+            }
+            env_vars.push((var, lease.port));
+        }
+    }
+
+    // The group id changes if the lease has to be allocated again
+    let group_id = Arc::new(Mutex::new(alloc_resp.group_id.clone()));
+    let (lost_tx, mut lost) = mpsc::unbounded_channel();
+    let heartbeat = tokio::spawn({
+        let (client, req, alloc_resp, group_id) = (client.clone(), req.clone(), alloc_resp.clone(), group_id.clone());
+        async move {
+            let _ = lost_tx.send(keep_alive(client, req, alloc_resp, group_id).await);
+        }
+    });
+
+    if verbose {
+        eprintln!("Running: {} {:?} with {}={}", command[0], &command[1..], env_name, port);
+    }
+    let mut backoff = RESTART_BACKOFF_MIN;
+    // Once keep_alive has given up on the lease there is nothing left to release
+    let mut lease_lost = false;
+    let failed = |e: std::io::Error| CliError::Local(format!("Failed to run {}: {}", command[0], e));
+    let status = loop {
+        let started = Instant::now();
+        let status = match supervise(&command, &env_vars, &mut signals, changes.as_mut(), &mut lost).await {
+            Err(e) => break Err(failed(e)),
+            Ok(Ended::Stopped(status)) => break Ok(status),
+            Ok(Ended::LeaseLost(e)) => {
+                lease_lost = true;
+                break Err(e);
+            }
+            Ok(Ended::Changed) => {
+                eprintln!("Files changed, restarting {}", command[0]);
+                continue;
+            }
+            Ok(Ended::Exited(status)) if !restart.restarts(status) => {
+                // Keep the lease for the fixed-up command, e.g. after a compile error
+                let Some(changes) = changes.as_mut() else {
+                    break Ok(status);
+                };
+                eprintln!("{} exited ({}), restarting when files change", command[0], status);
+                tokio::select! {
+                    _ = changes.next() => {}
+                    _ = signals.recv() => break Ok(status),
+                    Some(e) = lost.recv() => {
+                        lease_lost = true;
+                        break Err(e);
+                    }
+                }
+                eprintln!("Files changed, restarting {}", command[0]);
+                continue;
+            }
+            Ok(Ended::Exited(status)) => status,
+        };
+
+        if started.elapsed() >= STABLE_RUN {
+            backoff = RESTART_BACKOFF_MIN;
+        }
+        eprintln!("{} exited ({}), restarting in {}s", command[0], status, backoff.as_secs());
+        tokio::select! {
+            _ = time::sleep(backoff) => {}
+            _ = changed(changes.as_mut()) => {}
+            _ = signals.recv() => break Ok(status),
+            Some(e) = lost.recv() => {
+                lease_lost = true;
+                break Err(e);
+            }
+        }
+        backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+    };
+
+    heartbeat.abort();
+    if lease_lost {
+        return status;
+    }
+    let group_id = group_id.lock().unwrap().clone();
+    let released = match group_id {
+        Some(group_id) => client.release_group(&group_id).await,
+        None => client.release(port).await,
+    };
+    match released {
+        Ok(_) if verbose => eprintln!("Released port {}", port),
+        Ok(_) => {}
+        // The daemon still releases it once we exit, since we own the lease
+        Err(e) => eprintln!("Failed to release {}: {}", port, e),
+    }
+
+    status
+}
+
+/// Exit the way the command did: with its exit code, or, if a signal we
+/// forward stopped it, by the same signal, so shells and supervisors can tell.
+pub fn exit_like(status: ExitStatus) -> ! {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        if FORWARDED.contains(&signal) {
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+        }
+        // The shell's convention, for signals we would rather not die of, e.g. SIGSEGV
+        std::process::exit(128 + signal);
+    }
+    std::process::exit(status.code().unwrap_or(1))
+}
+
+/// Signals passed on to the command.
+#[cfg(unix)]
+const FORWARDED: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Heartbeat the lease a few times per TTL, so one or two failed attempts,
+/// e.g. while the daemon restarts, do not let it expire. A lost lease is
+/// allocated again on the same ports; returns if that fails, after which the
+/// command must not keep its ports.
+async fn keep_alive(
+    client: PortManagerClient,
+    request: AllocateRequest,
+    mut held: AllocateResponse,
+    group_id: Arc<Mutex<Option<String>>>,
+) -> CliError {
+    let ttl = Duration::from_secs(held.lease.ttl_seconds);
+    let mut interval = time::interval((ttl / 3).max(HEARTBEAT_MIN));
+    // The first tick is immediate, and the lease was only just allocated
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match renew(&client, &held).await {
+            Ok(true) => {}
+            Ok(false) => {
+                held = match reclaim(&client, &request, &held).await {
+                    Ok(reclaimed) => reclaimed,
+                    Err(e) => return e,
+                };
+                *group_id.lock().unwrap() = held.group_id.clone();
+                eprintln!("Lease on port {} was lost, allocated it again", held.port);
+            }
+            // The daemon may be restarting, and the TTL allows a few misses
+            Err(portmanager::Error::Http(e)) => eprintln!("Heartbeat failed, retrying: {}", e),
+            Err(e) => return CliError::from(e).context("Heartbeat failed"),
+        }
+    }
+}
+
+/// Heartbeat the leases in `held`, or return `false` if they are gone.
+///
+/// Heartbeats go by port, so a port that expired or was released elsewhere
+/// and then leased again, even to a caller in our namespace, would be kept
+/// alive for its new holder. The leases are compared first to rule that out.
+async fn renew(client: &PortManagerClient, held: &AllocateResponse) -> portmanager::Result<bool> {
+    let current = client.list().await?;
+    let ours = held.leases.iter().all(|lease| {
+        current.iter().any(|l| l.port == lease.port && l.allocated_at == lease.allocated_at && l.service_name == lease.service_name)
+    });
+    if !ours {
+        return Ok(false);
+    }
+    let result = match &held.group_id {
+        Some(group_id) => client.heartbeat_group(group_id).await,
+        None => client.heartbeat(held.port).await,
+    };
+    match result {
+        Ok(()) => Ok(true),
+        Err(portmanager::Error::Api(e)) if matches!(e.code, ErrorCode::PortNotLeased | ErrorCode::GroupNotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Allocate the ports of a lost lease again. Any other ports would not be the
+/// ones the command was started with, so those are given back.
+async fn reclaim(
+    client: &PortManagerClient,
+    request: &AllocateRequest,
+    held: &AllocateResponse,
+) -> Result<AllocateResponse, CliError> {
+    let request = AllocateRequest { preferred_port: Some(held.port), ..request.clone() };
+    let context = format!("Lease on port {} was lost and could not be allocated again", held.port);
+    let reclaimed = client.allocate(&request).await.context(&context)?;
+    if ports(&reclaimed) == ports(held) {
+        return Ok(reclaimed);
+    }
+    let _ = match &reclaimed.group_id {
+        Some(group_id) => client.release_group(group_id).await,
+        None => client.release(reclaimed.port).await,
+    };
+    Err(CliError::Local(format!("Lease on port {} was lost and the port has been taken since", held.port)))
+}
+
+fn ports(response: &AllocateResponse) -> Vec<u16> {
+    let mut ports: Vec<u16> = response.leases.iter().map(|l| l.port).collect();
+    ports.sort_unstable();
+    ports
+}
+
+fn spawn(command: &[String], env_vars: &[(String, u16)]) -> std::io::Result<Child> {
+    Command::new(&command[0])
+        .args(&command[1..])
+        .envs(env_vars.iter().map(|(var, port)| (var, port.to_string())))
+        .spawn()
+}
+
+/// How one run of the command ended.
+enum Ended {
+    /// It exited by itself.
+    Exited(ExitStatus),
+    /// It exited after portctl was told to stop.
+    Stopped(ExitStatus),
+    /// Watched files changed and it was stopped, to be started again.
+    Changed,
+    /// The lease was lost and it was stopped, since its ports may be someone else's now.
+    LeaseLost(CliError),
+}
+
+/// Run the command once, passing signals sent to portctl on to it.
+async fn supervise(
+    command: &[String],
+    env_vars: &[(String, u16)],
+    signals: &mut Signals,
+    mut changes: Option<&mut Changes>,
+    lost: &mut mpsc::UnboundedReceiver<CliError>,
+) -> std::io::Result<Ended> {
+    let mut child = spawn(command, env_vars)?;
+    let mut stopping = false;
+    let mut restarting = false;
+    let mut lease_lost = None;
+    let mut kill_at = None;
+    loop {
+        tokio::select! {
+            status = child.wait() => {
+                let status = status?;
+                return Ok(if let Some(e) = lease_lost {
+                    Ended::LeaseLost(e)
+                } else if stopping {
+                    Ended::Stopped(status)
+                } else if restarting {
+                    Ended::Changed
+                } else {
+                    Ended::Exited(status)
+                });
+            }
+            signal = signals.recv() => {
+                stopping = true;
+                if let Some(signal) = signal {
+                    forward(&child, signal);
+                }
+            }
+            _ = changed(changes.as_deref_mut()), if !stopping && !restarting => {
+                restarting = true;
+                kill_at = Some(Instant::now() + STOP_TIMEOUT);
+                terminate(&mut child)?;
+            }
+            Some(e) = lost.recv(), if lease_lost.is_none() => {
+                lease_lost = Some(e);
+                kill_at = Some(Instant::now() + STOP_TIMEOUT);
+                terminate(&mut child)?;
+            }
+            _ = time::sleep_until(kill_at.unwrap_or_else(Instant::now)), if kill_at.is_some() => {
+                kill_at = None;
+                child.start_kill()?;
+            }
+        }
+    }
+}
+
+/// Ask the command to stop.
+#[cfg(unix)]
+fn terminate(child: &mut Child) -> std::io::Result<()> {
+    forward(child, libc::SIGTERM);
+    Ok(())
+}
+
+/// Stop the command; there is no SIGTERM to ask it with.
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) -> std::io::Result<()> {
+    child.start_kill()
+}
+
+#[cfg(unix)]
+fn forward(child: &Child, signal: libc::c_int) {
+    // The pid cannot be reused by another process until `wait` has reaped the child
+    if let Some(pid) = child.id().and_then(|pid| libc::pid_t::try_from(pid).ok()) {
+        unsafe { libc::kill(pid, signal) };
+    }
+}
+
+#[cfg(not(unix))]
+fn forward(_child: &Child, _signal: i32) {}
+
+/// Signals sent to portctl, to be passed on to the command.
+#[cfg(unix)]
+struct Signals {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    /// Listening replaces the default action, so portctl itself is no longer killed by these.
+    fn listen() -> std::io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Signals {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// The next signal to pass on, or `None` if the command has it already.
+    async fn recv(&mut self) -> Option<libc::c_int> {
+        tokio::select! {
+            // Ctrl-C reaches the terminal's whole foreground process group, so the
+            // command has it already; a second one could cut its shutdown short
+            _ = self.interrupt.recv() => (!in_terminal_foreground()).then_some(libc::SIGINT),
+            _ = self.terminate.recv() => Some(libc::SIGTERM),
+            _ = self.hangup.recv() => Some(libc::SIGHUP),
+        }
+    }
+}
+
+/// Ctrl-C, which reaches every process on the console, the command included.
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn listen() -> std::io::Result<Self> {
+        Ok(Signals)
+    }
+
+    async fn recv(&mut self) -> Option<i32> {
+        let _ = tokio::signal::ctrl_c().await;
+        None
+    }
+}
+
+/// Changes to the files under the `--watch` paths.
+struct Changes {
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<()>,
+}
+
+impl Changes {
+    fn watch(paths: &[PathBuf]) -> Result<Self, CliError> {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            // Reading files, e.g. by the command itself, is no reason to restart
+            if event.is_ok_and(|e| !e.kind.is_access()) {
+                let _ = sender.send(());
+            }
+        })
+        .map_err(|e| CliError::Local(format!("Failed to watch files: {}", e)))?;
+        for path in paths {
+            watcher
+                .watch(path, RecursiveMode::Recursive)
+                .map_err(|e| CliError::Local(format!("Failed to watch {}: {}", path.display(), e)))?;
+        }
+        Ok(Changes { _watcher: watcher, events })
+    }
+
+    /// Wait for a change, and then until no more follow for a moment.
+    async fn next(&mut self) {
+        if self.events.recv().await.is_none() {
+            return std::future::pending().await;
+        }
+        while let Ok(Some(())) = time::timeout(WATCH_SETTLE, self.events.recv()).await {}
+    }
+}
+
+/// Wait for the next change, or forever without `--watch`.
+async fn changed(changes: Option<&mut Changes>) {
+    match changes {
+        Some(changes) => changes.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Whether portctl, and with it the command, is in the foreground of the terminal on stdin.
+#[cfg(unix)]
+fn in_terminal_foreground() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() }
+}
@@ -0,0 +1,319 @@
+use chrono::Utc;
+use common::{EventKind, Lease, LeaseEvent};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table, TableState},
+    DefaultTerminal, Frame,
+};
+use portmanager::PortManagerClient;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+/// How often the table is redrawn, so ages and expiry countdowns stay current.
+const TICK: Duration = Duration::from_millis(250);
+
+/// What the background feed reports to the UI.
+enum Update {
+    /// Full lease list, sent on (re)connect and on every poll.
+    Snapshot(Vec<Lease>),
+    Event(Box<LeaseEvent>),
+    /// Whether changes arrive live over the event stream or by polling `/list`.
+    Mode(bool),
+}
+
+struct App {
+    leases: BTreeMap<u16, Lease>,
+    table: TableState,
+    /// Port of the highlighted lease; the row index follows it as leases come and go.
+    selected: Option<u16>,
+    live: bool,
+    status: String,
+}
+
+impl App {
+    fn selected(&self) -> Option<&Lease> {
+        self.selected.and_then(|port| self.leases.get(&port))
+    }
+
+    /// Highlight the given row, clamped to the table, and remember its port.
+    fn select_row(&mut self, row: Option<usize>) {
+        let row = row.filter(|_| !self.leases.is_empty()).map(|i| i.min(self.leases.len() - 1));
+        self.table.select(row);
+        self.selected = row.and_then(|i| self.leases.keys().nth(i).copied());
+    }
+
+    fn select_next(&mut self) {
+        self.select_row(Some(self.table.selected().map_or(0, |i| i + 1)));
+    }
+
+    fn select_previous(&mut self) {
+        self.select_row(Some(self.table.selected().map_or(0, |i| i.saturating_sub(1))));
+    }
+
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Snapshot(leases) => {
+                self.leases = leases.into_iter().map(|l| (l.port, l)).collect();
+            }
+            Update::Event(event) => match (event.kind, event.lease) {
+                (EventKind::Allocated | EventKind::Heartbeat, Some(lease)) => {
+                    self.leases.insert(lease.port, lease);
+                }
+                (EventKind::Released | EventKind::Expired, _) => {
+                    self.leases.remove(&event.port);
+                }
+                _ => {}
+            },
+            Update::Mode(live) => self.live = live,
+        }
+
+        // Stay on the selected lease wherever it moved; if it is gone, stay on its row
+        let row = self
+            .selected
+            .and_then(|port| self.leases.keys().position(|&p| p == port))
+            .or(self.table.selected())
+            .unwrap_or(0);
+        self.select_row(Some(row));
+    }
+}
+
+/// Run the live lease view until the user quits.
+pub async fn run(client: PortManagerClient, interval: Duration) -> std::io::Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let feed = tokio::spawn(feed(client.clone(), interval, tx));
+
+    let mut terminal = ratatui::init();
+    let mut app = App {
+        leases: BTreeMap::new(),
+        table: TableState::default(),
+        selected: None,
+        live: false,
+        status: String::new(),
+    };
+    let result = event_loop(&mut terminal, &mut app, &client, &mut rx).await;
+    ratatui::restore();
+    feed.abort();
+    result
+}
+
+async fn event_loop(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    client: &PortManagerClient,
+    rx: &mut mpsc::UnboundedReceiver<Update>,
+) -> std::io::Result<()> {
+    let mut tick = time::interval(TICK);
+    loop {
+        terminal.draw(|frame| draw(frame, app))?;
+
+        tokio::select! {
+            Some(update) = rx.recv() => {
+                app.apply(update);
+                continue;
+            }
+            _ = tick.tick() => {}
+        }
+
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => app.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => app.select_previous(),
+                KeyCode::Char('r') => {
+                    if let Some(port) = app.selected().map(|l| l.port) {
+                        app.status = release(client, port).await;
+                    }
+                }
+                KeyCode::Char('e') => {
+                    if let Some(port) = app.selected().map(|l| l.port) {
+                        app.status = extend(client, port).await;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn release(client: &PortManagerClient, port: u16) -> String {
+    match client.release(port).await {
+        Ok(()) => format!("Released port {}", port),
+        Err(e) => format!("Failed to release {}: {}", port, e),
+    }
+}
+
+async fn extend(client: &PortManagerClient, port: u16) -> String {
+    match client.heartbeat(port).await {
+        Ok(()) => format!("Extended lease on port {}", port),
+        Err(e) => format!("Failed to extend {}: {}", port, e),
+    }
+}
+
+/// Keep the UI supplied with leases: follow the event stream while it is up,
+/// and poll `/list` every `interval` while it is not.
+async fn feed(client: PortManagerClient, interval: Duration, tx: mpsc::UnboundedSender<Update>) {
+    loop {
+        // Subscribe before taking the snapshot so no change falls in between
+        if let Ok(mut stream) = client.events().await {
+            if let Ok(leases) = client.list().await {
+                let _ = tx.send(Update::Snapshot(leases));
+                let _ = tx.send(Update::Mode(true));
+
+                while let Ok(Some(event)) = stream.next().await {
+                    if tx.send(Update::Event(Box::new(event))).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+
+        let _ = tx.send(Update::Mode(false));
+        if let Ok(leases) = client.list().await {
+            let _ = tx.send(Update::Snapshot(leases));
+        }
+        time::sleep(interval).await;
+        if tx.is_closed() {
+            return;
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, app: &mut App) {
+    let [header, body, footer] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let mode = if app.live { "live".green() } else { "polling".yellow() };
+    frame.render_widget(
+        Line::from(vec![
+            "portctl watch ".bold(),
+            format!("- {} lease(s) - ", app.leases.len()).into(),
+            mode,
+        ]),
+        header,
+    );
+
+    let now = Utc::now();
+    let rows = app.leases.values().map(|lease| {
+        let age = (now - lease.allocated_at).num_seconds();
+        let expires = lease.last_heartbeat + chrono::Duration::seconds(lease.ttl_seconds as i64);
+        let remaining = (expires - now).num_seconds();
+        let remaining_cell = if remaining < 30 {
+            Line::from(format_duration(remaining)).red()
+        } else {
+            Line::from(format_duration(remaining))
+        };
+        Row::new(vec![
+            Line::from(lease.port.to_string()),
+            Line::from(lease.service_name.clone()),
+            Line::from(lease.tags.join(",")),
+            Line::from(format_duration(age)),
+            remaining_cell,
+            Line::from(lease.owner_pid.map(|p| p.to_string()).unwrap_or_else(|| "-".to_string())),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(6),
+            Constraint::Fill(2),
+            Constraint::Fill(1),
+            Constraint::Length(9),
+            Constraint::Length(11),
+            Constraint::Length(8),
+        ],
+    )
+    .header(Row::new(["PORT", "SERVICE", "TAGS", "AGE", "EXPIRES IN", "OWNER"]).bold())
+    .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+    .block(Block::bordered());
+    frame.render_stateful_widget(table, body, &mut app.table);
+
+    frame.render_widget(
+        Paragraph::new(format!("q quit  j/k move  r release  e extend   {}", app.status)).dim(),
+        footer,
+    );
+}
+
+/// Render seconds as e.g. "45s", "12m05s" or "3h07m".
+fn format_duration(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    if seconds < 60 {
+        format!("{}s", seconds)
+    } else if seconds < 3600 {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease(port: u16) -> Lease {
+        Lease {
+            port,
+            service_name: format!("service-{}", port),
+            allocated_at: Utc::now(),
+            last_heartbeat: Utc::now(),
+            ttl_seconds: 60,
+            tags: Vec::new(),
+            owner_pid: None,
+            owner_start_time: None,
+            group_id: None,
+            role: None,
+            pool: "default".to_string(),
+            namespace: "default".to_string(),
+        }
+    }
+
+    fn event(kind: EventKind, port: u16) -> Update {
+        Update::Event(Box::new(LeaseEvent { kind, port, lease: Some(lease(port)), conflict: None, at: Utc::now() }))
+    }
+
+    #[test]
+    fn selection_follows_the_lease_across_live_changes() {
+        let mut app = App {
+            leases: BTreeMap::new(),
+            table: TableState::default(),
+            selected: None,
+            live: true,
+            status: String::new(),
+        };
+        app.apply(Update::Snapshot(vec![lease(8001), lease(8003)]));
+        app.select_next();
+        assert_eq!(app.selected().map(|l| l.port), Some(8003));
+
+        // A lease sorting before the selection shifts its row, not the selection
+        app.apply(event(EventKind::Allocated, 8000));
+        assert_eq!(app.selected().map(|l| l.port), Some(8003));
+        assert_eq!(app.table.selected(), Some(2));
+
+        app.apply(event(EventKind::Released, 8001));
+        assert_eq!(app.selected().map(|l| l.port), Some(8003));
+        assert_eq!(app.table.selected(), Some(1));
+
+        // When the selected lease goes away the highlight stays on its row
+        app.apply(event(EventKind::Released, 8003));
+        assert_eq!(app.selected().map(|l| l.port), Some(8000));
+
+        app.apply(event(EventKind::Expired, 8000));
+        assert!(app.selected().is_none());
+        assert_eq!(app.table.selected(), None);
+    }
+}
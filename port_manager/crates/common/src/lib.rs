@@ -1,7 +1,43 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Pool used when a request does not name one. Its range comes from the daemon configuration.
+pub const DEFAULT_POOL: &str = "default";
+
+fn default_pool() -> String {
+    DEFAULT_POOL.to_string()
+}
+
+/// Namespace of the user running the daemon.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Namespace of TCP callers without a token, who cannot be told apart.
+/// No user name starts with `@`, so it never clashes with a user's namespace.
+pub const ANONYMOUS_NAMESPACE: &str = "@tcp";
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+/// Unix socket the daemon serves its API on unless configured otherwise:
+/// `$XDG_RUNTIME_DIR/portmanager.sock`, or `~/.portmanager/portmanager.sock`
+/// where there is no runtime directory.
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("portmanager.sock"),
+        None => std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(".portmanager")
+            .join("portmanager.sock"),
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct Lease {
     pub port: u16,
     pub service_name: String,
@@ -9,35 +45,650 @@ pub struct Lease {
     pub last_heartbeat: DateTime<Utc>,
     pub ttl_seconds: u64,
     pub tags: Vec<String>,
+    /// Process that holds the lease. The lease is released as soon as it exits.
+    #[serde(default)]
+    pub owner_pid: Option<u32>,
+    /// Start time of the owner process, guards against PID reuse.
+    #[serde(default)]
+    pub owner_start_time: Option<u64>,
+    /// Shared by all leases allocated together as one block.
+    #[serde(default)]
+    pub group_id: Option<String>,
+    /// Name of this port within its block, e.g. "http" or "grpc".
+    #[serde(default)]
+    pub role: Option<String>,
+    /// Pool the port was allocated from.
+    #[serde(default = "default_pool")]
+    pub pool: String,
+    /// Namespace of the user that allocated the port. Only they and admins can see or release it.
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct AllocateRequest {
     pub service_name: String,
     pub ttl_seconds: Option<u64>,
     pub tags: Option<Vec<String>>,
+    /// Port to try first. Falls back to the service's sticky port, then to
+    /// the first free port in range, if it is unavailable.
+    #[serde(default)]
+    pub preferred_port: Option<u16>,
+    /// Process that owns the lease; it is released when that process exits.
+    #[serde(default)]
+    pub owner_pid: Option<u32>,
+    /// Start time of the owner process. Looked up by the daemon if omitted.
+    #[serde(default)]
+    pub owner_start_time: Option<u64>,
+    /// Number of ports to allocate as one group. Defaults to the number of
+    /// roles, or 1.
+    #[serde(default)]
+    pub count: Option<u16>,
+    /// Require the ports of a group to be consecutive.
+    #[serde(default)]
+    pub contiguous: bool,
+    /// Role names for the ports of a group, in allocation order.
+    #[serde(default)]
+    pub roles: Option<Vec<String>>,
+    /// Pool to allocate from. Defaults to `DEFAULT_POOL`.
+    #[serde(default)]
+    pub pool: Option<String>,
+}
+
+impl AllocateRequest {
+    /// One port for `service_name`, with everything else left to the daemon.
+    pub fn new(service_name: impl Into<String>) -> Self {
+        AllocateRequest { service_name: service_name.into(), ..Default::default() }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct AllocateResponse {
     pub port: u16,
     pub lease: Lease,
+    /// Candidate ports that were passed over, and why.
+    #[serde(default)]
+    pub skipped: Vec<SkippedPort>,
+    /// Set when more than one port or any roles were requested.
+    #[serde(default)]
+    pub group_id: Option<String>,
+    /// Every lease created by this request, starting with `lease`.
+    #[serde(default)]
+    pub leases: Vec<Lease>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The port lies outside the daemon's allocation range.
+    OutOfRange,
+    /// The port is held by another lease.
+    Leased,
+    /// Another process is already listening on the port.
+    InUse,
+    /// The port could not be bound for some other reason, e.g. permissions.
+    BindFailed,
+    /// The port is on the daemon's exclusion list.
+    Excluded,
+    /// The port is permanently reserved for another service.
+    Reserved,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ReleaseRequest {
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SkippedPort {
     pub port: u16,
+    pub reason: SkipReason,
+    pub detail: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Identifies the leases to act on: a single port, or every port of a group.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ReleaseRequest {
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub group_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct HeartbeatRequest {
-    pub port: u16,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub group_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct LookupResponse {
     pub service_name: String,
     pub port: Option<u16>,
     pub all_ports: Vec<u16>,
     pub lease: Option<Lease>,
 }
+
+/// What happened to a lease, as published on the daemon's event stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Allocated,
+    /// Released by a client, or because the owner process exited.
+    Released,
+    Heartbeat,
+    /// The TTL ran out without a heartbeat.
+    Expired,
+    /// An unmanaged listener appeared in a pool's range.
+    Conflict,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Allocated => "allocated",
+            EventKind::Released => "released",
+            EventKind::Heartbeat => "heartbeat",
+            EventKind::Expired => "expired",
+            EventKind::Conflict => "conflict",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LeaseEvent {
+    pub kind: EventKind,
+    pub port: u16,
+    /// The lease as it was when the event happened. Not set for conflicts.
+    #[serde(default)]
+    pub lease: Option<Lease>,
+    /// Set for conflict events only.
+    #[serde(default)]
+    pub conflict: Option<Conflict>,
+    pub at: DateTime<Utc>,
+}
+
+impl LeaseEvent {
+    pub fn service_name(&self) -> Option<&str> {
+        self.lease.as_ref().map(|l| l.service_name.as_str())
+    }
+}
+
+/// A listening socket inside the managed range that no lease accounts for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Conflict {
+    pub port: u16,
+    /// Owning process, if it could be determined (requires access to /proc/<pid>/fd).
+    pub pid: Option<u32>,
+    pub command: Option<String>,
+    pub detected_at: DateTime<Utc>,
+}
+
+/// Order in which a pool hands out free ports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum AllocationStrategy {
+    /// Lowest free port first.
+    #[default]
+    FirstFree,
+    /// Highest free port first.
+    LastFree,
+    /// Any free port, chosen at random.
+    Random,
+}
+
+impl AllocationStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AllocationStrategy::FirstFree => "first_free",
+            AllocationStrategy::LastFree => "last_free",
+            AllocationStrategy::Random => "random",
+        }
+    }
+}
+
+impl FromStr for AllocationStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first_free" => Ok(AllocationStrategy::FirstFree),
+            "last_free" => Ok(AllocationStrategy::LastFree),
+            "random" => Ok(AllocationStrategy::Random),
+            other => Err(format!("unknown allocation strategy: {}", other)),
+        }
+    }
+}
+
+/// A named port range with its own allocation policy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Pool {
+    pub name: String,
+    pub min_port: u16,
+    pub max_port: u16,
+    pub default_ttl: u64,
+    #[serde(default)]
+    pub strategy: AllocationStrategy,
+}
+
+/// Body of `PUT /pools/{name}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PoolSpec {
+    pub min_port: u16,
+    pub max_port: u16,
+    pub default_ttl: Option<u64>,
+    #[serde(default)]
+    pub strategy: AllocationStrategy,
+}
+
+/// A port set aside permanently for one service. Other services never get it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Reservation {
+    pub port: u16,
+    pub service_name: String,
+    pub reserved_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ReserveRequest {
+    pub port: u16,
+    pub service_name: String,
+}
+
+/// What happened to a lease, as recorded in the daemon's audit history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryKind {
+    Allocated,
+    /// Released by a client, or because the owner process exited.
+    Released,
+    /// Removed by the cleaner after its TTL ran out.
+    Expired,
+    /// A heartbeat arrived after more than half the TTL had passed without one.
+    HeartbeatGap,
+}
+
+impl HistoryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HistoryKind::Allocated => "allocated",
+            HistoryKind::Released => "released",
+            HistoryKind::Expired => "expired",
+            HistoryKind::HeartbeatGap => "heartbeat_gap",
+        }
+    }
+}
+
+impl FromStr for HistoryKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allocated" => Ok(HistoryKind::Allocated),
+            "released" => Ok(HistoryKind::Released),
+            "expired" => Ok(HistoryKind::Expired),
+            "heartbeat_gap" => Ok(HistoryKind::HeartbeatGap),
+            other => Err(format!("unknown history kind: {}", other)),
+        }
+    }
+}
+
+/// One row of the lease audit history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub port: u16,
+    pub service_name: String,
+    /// Namespace of the lease the entry is about.
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    pub kind: HistoryKind,
+    /// Human-readable cause, e.g. "owner process 4711 exited".
+    pub reason: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+/// Filters for `GET /v1/history`. Results are newest first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub struct HistoryQuery {
+    /// Only entries for this service.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    /// Only entries for this port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Only entries from this namespace; other namespaces need admin rights.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Only entries at or after this time (RFC 3339).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+    /// Maximum number of entries (default 100).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
+
+/// Version of the `StateExport` format written by this build. Older versions are still imported.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// The port a service held last, offered to it again on its next allocation
+/// in the same namespace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct StickyPort {
+    pub service_name: String,
+    pub port: u16,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+}
+
+/// Lease state as written by `GET /v1/export` and read by `POST /v1/import`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct StateExport {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    #[serde(default)]
+    pub leases: Vec<Lease>,
+    #[serde(default)]
+    pub reservations: Vec<Reservation>,
+    #[serde(default)]
+    pub sticky_ports: Vec<StickyPort>,
+}
+
+/// How an import treats the daemon's existing state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// Keep existing state and add what does not clash with it.
+    #[default]
+    Merge,
+    /// Drop all leases, reservations and sticky ports first.
+    Replace,
+}
+
+impl ImportMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportMode::Merge => "merge",
+            ImportMode::Replace => "replace",
+        }
+    }
+}
+
+impl FromStr for ImportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge" => Ok(ImportMode::Merge),
+            "replace" => Ok(ImportMode::Replace),
+            other => Err(format!("unknown import mode: {}", other)),
+        }
+    }
+}
+
+/// Query parameters of `POST /v1/import`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub struct ImportParams {
+    /// `merge` (default) or `replace`.
+    #[serde(default)]
+    pub mode: ImportMode,
+}
+
+/// Query parameters of `GET /v1/conflicts`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub struct ConflictsParams {
+    /// Rescan now instead of returning the latest periodic scan.
+    #[serde(default)]
+    pub refresh: bool,
+}
+
+/// Kind of entry an `ImportConflict` refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ImportItem {
+    Lease,
+    Reservation,
+    StickyPort,
+}
+
+/// An entry of the imported document that was skipped, and why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ImportConflict {
+    pub item: ImportItem,
+    pub port: u16,
+    pub service_name: String,
+    pub reason: String,
+}
+
+/// Result of `POST /v1/import`. Entries already present unchanged are neither imported nor conflicts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ImportReport {
+    pub mode: ImportMode,
+    pub leases: usize,
+    pub reservations: usize,
+    pub sticky_ports: usize,
+    #[serde(default)]
+    pub conflicts: Vec<ImportConflict>,
+}
+
+/// What an API token may do. Each scope includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    /// List and look up leases, pools, reservations, history and events.
+    Read,
+    /// Also allocate, heartbeat and release leases in the token's namespace.
+    Allocate,
+    /// Everything: all namespaces, pools, reservations, export/import and tokens.
+    Admin,
+}
+
+impl TokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Allocate => "allocate",
+            TokenScope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for TokenScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(TokenScope::Read),
+            "allocate" => Ok(TokenScope::Allocate),
+            "admin" => Ok(TokenScope::Admin),
+            other => Err(format!("unknown token scope: {}", other)),
+        }
+    }
+}
+
+/// An API token as listed by the daemon. The secret itself is only shown once, on creation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    /// Namespace that requests with this token act in.
+    pub namespace: String,
+    pub scope: TokenScope,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body of `POST /v1/tokens`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub scope: TokenScope,
+    /// Defaults to the namespace of the caller creating the token.
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+/// Response of `POST /v1/tokens`: the new token and its secret, to be sent as
+/// `Authorization: Bearer <secret>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CreatedToken {
+    pub token: ApiToken,
+    pub secret: String,
+}
+
+/// Machine-readable error codes returned in `ApiError::code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request body or parameters are malformed or inconsistent.
+    InvalidRequest,
+    /// The service name is empty, too long or contains whitespace.
+    InvalidServiceName,
+    /// The requested pool does not exist.
+    PoolNotFound,
+    /// The pool has no (or not enough) free ports left.
+    PoolExhausted,
+    /// The port has no active lease.
+    PortNotLeased,
+    /// No active lease belongs to the group.
+    GroupNotFound,
+    /// The port is on the daemon's exclusion list.
+    PortExcluded,
+    /// Some other resource, such as a reservation, does not exist.
+    NotFound,
+    /// The request clashes with existing state, e.g. active leases.
+    Conflict,
+    /// No valid API token was presented where one is required.
+    Unauthorized,
+    /// The caller may not act on this resource, e.g. another namespace's lease.
+    Forbidden,
+    /// The allocation would take the caller's namespace over its port quota.
+    QuotaExceeded,
+    /// The lease database could not be read or written.
+    DatabaseError,
+    /// Anything else; also used by clients for non-JSON error responses.
+    Internal,
+}
+
+impl ErrorCode {
+    /// HTTP status code the daemon answers with for this error.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidServiceName | ErrorCode::PortExcluded => 400,
+            ErrorCode::PoolNotFound | ErrorCode::PortNotLeased | ErrorCode::GroupNotFound | ErrorCode::NotFound => 404,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::Forbidden => 403,
+            ErrorCode::Conflict => 409,
+            ErrorCode::QuotaExceeded => 429,
+            ErrorCode::PoolExhausted => 503,
+            ErrorCode::DatabaseError | ErrorCode::Internal => 500,
+        }
+    }
+}
+
+/// JSON body of every error response: `{"code": ..., "message": ..., "details": ...}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidRequest, message)
+    }
+
+    pub fn invalid_service_name(service_name: &str) -> Self {
+        Self::new(
+            ErrorCode::InvalidServiceName,
+            format!("Invalid service name '{}': must be 1-128 characters without whitespace", service_name),
+        )
+        .with_details(serde_json::json!({ "service_name": service_name }))
+    }
+
+    pub fn pool_not_found(pool: &str) -> Self {
+        Self::new(ErrorCode::PoolNotFound, format!("Pool '{}' does not exist", pool))
+            .with_details(serde_json::json!({ "pool": pool }))
+    }
+
+    pub fn port_not_leased(port: u16) -> Self {
+        Self::new(ErrorCode::PortNotLeased, format!("Port {} is not leased", port))
+            .with_details(serde_json::json!({ "port": port }))
+    }
+
+    pub fn group_not_found(group_id: &str) -> Self {
+        Self::new(ErrorCode::GroupNotFound, format!("No active leases in group '{}'", group_id))
+            .with_details(serde_json::json!({ "group_id": group_id }))
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Forbidden, message)
+    }
+
+    pub fn database(error: impl fmt::Display) -> Self {
+        Self::new(ErrorCode::DatabaseError, format!("Database error: {}", error))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Check a service name: 1-128 characters, no whitespace or control characters.
+pub fn validate_service_name(service_name: &str) -> Result<(), ApiError> {
+    let valid = !service_name.is_empty()
+        && service_name.chars().count() <= 128
+        && !service_name.chars().any(|c| c.is_whitespace() || c.is_control());
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::invalid_service_name(service_name))
+    }
+}
@@ -0,0 +1,330 @@
+use crate::probe::PortProbe;
+use common::{AllocationStrategy, Lease, Pool, Reservation, SkipReason, SkippedPort};
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// Picks free ports for an allocation, remembering why candidates were passed over.
+///
+/// Each port is checked against the range, the current leases and the OS at
+/// most once, so scanning for a contiguous block stays cheap.
+pub struct PortSelector<'a> {
+    min_port: u16,
+    max_port: u16,
+    strategy: AllocationStrategy,
+    leases: &'a HashMap<u16, Lease>,
+    excluded: &'a [RangeInclusive<u16>],
+    reservations: &'a HashMap<u16, Reservation>,
+    service_name: &'a str,
+    probe: PortProbe,
+    checked: HashMap<u16, bool>,
+    pub skipped: Vec<SkippedPort>,
+}
+
+impl<'a> PortSelector<'a> {
+    /// Ports reserved for `service_name` are allowed; ports reserved for anyone else are not.
+    pub fn new(
+        pool: &Pool,
+        leases: &'a HashMap<u16, Lease>,
+        excluded: &'a [RangeInclusive<u16>],
+        reservations: &'a HashMap<u16, Reservation>,
+        service_name: &'a str,
+    ) -> Self {
+        Self {
+            min_port: pool.min_port,
+            max_port: pool.max_port,
+            strategy: pool.strategy,
+            leases,
+            excluded,
+            reservations,
+            service_name,
+            probe: PortProbe::new(),
+            checked: HashMap::new(),
+            skipped: Vec::new(),
+        }
+    }
+
+    /// Ask `probe` about ports instead of the OS.
+    #[cfg(test)]
+    fn with_probe(mut self, probe: PortProbe) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Select `count` ports, trying the `preferred` ones first.
+    ///
+    /// With `contiguous`, the preferred ports are tried as the start of the block.
+    /// Returns `None` if the range cannot satisfy the request.
+    pub fn select(&mut self, count: usize, contiguous: bool, preferred: &[u16]) -> Option<Vec<u16>> {
+        if contiguous {
+            self.select_contiguous(count, preferred)
+        } else {
+            self.select_any(count, preferred)
+        }
+    }
+
+    fn select_any(&mut self, count: usize, preferred: &[u16]) -> Option<Vec<u16>> {
+        let mut ports = Vec::with_capacity(count);
+
+        for &port in preferred {
+            if ports.len() == count {
+                break;
+            }
+            if !ports.contains(&port) && self.check(port, true) {
+                ports.push(port);
+            }
+        }
+
+        for port in self.scan_order(self.max_port) {
+            if ports.len() == count {
+                break;
+            }
+            if !ports.contains(&port) && self.check(port, false) {
+                ports.push(port);
+            }
+        }
+
+        (ports.len() == count).then_some(ports)
+    }
+
+    fn select_contiguous(&mut self, count: usize, preferred: &[u16]) -> Option<Vec<u16>> {
+        for &start in preferred {
+            if self.block_free(start, count, true) {
+                return Some(block(start, count));
+            }
+        }
+
+        // Only starts that leave room for the whole block inside the range
+        let last_start = self.max_port.checked_sub(count as u16 - 1)?;
+        for start in self.scan_order(last_start) {
+            if self.block_free(start, count, false) {
+                return Some(block(start, count));
+            }
+        }
+        None
+    }
+
+    /// Candidate ports from `min_port` to `last`, in the order the pool's strategy wants them tried.
+    fn scan_order(&self, last: u16) -> Vec<u16> {
+        let mut ports: Vec<u16> = (self.min_port..=last).collect();
+        match self.strategy {
+            AllocationStrategy::FirstFree => {}
+            AllocationStrategy::LastFree => ports.reverse(),
+            AllocationStrategy::Random => ports.shuffle(&mut rand::thread_rng()),
+        }
+        ports
+    }
+
+    fn block_free(&mut self, start: u16, count: usize, report_leased: bool) -> bool {
+        if u32::from(start) + count as u32 - 1 > u32::from(u16::MAX) {
+            return false;
+        }
+        // Check every port so all reasons get recorded, not just the first
+        let mut free = true;
+        for port in block(start, count) {
+            free &= self.check(port, report_leased);
+        }
+        free
+    }
+
+    /// Check one candidate, recording why it was skipped the first time it fails.
+    /// Leased, excluded and reserved ports are expected while scanning and are only
+    /// reported for preferred candidates.
+    fn check(&mut self, port: u16, report_leased: bool) -> bool {
+        if let Some(free) = self.checked.get(&port) {
+            return *free;
+        }
+
+        let result = if !(self.min_port..=self.max_port).contains(&port) {
+            Err((SkipReason::OutOfRange, None))
+        } else if self.excluded.iter().any(|r| r.contains(&port)) {
+            Err((SkipReason::Excluded, None))
+        } else if let Some(lease) = self.leases.get(&port) {
+            Err((SkipReason::Leased, Some(format!("leased by '{}'", lease.service_name))))
+        } else if let Some(reservation) = self.reservations.get(&port).filter(|r| r.service_name != self.service_name) {
+            Err((SkipReason::Reserved, Some(format!("reserved for '{}'", reservation.service_name))))
+        } else {
+            self.probe.check(port).map_err(|(reason, detail)| (reason, Some(detail)))
+        };
+
+        let free = match result {
+            Ok(()) => true,
+            // Excluded and reserved ports are just as expected as leased ones
+            Err((SkipReason::Leased | SkipReason::Excluded | SkipReason::Reserved, _)) if !report_leased => false,
+            Err((reason, detail)) => {
+                self.skipped.push(SkippedPort { port, reason, detail });
+                false
+            }
+        };
+        self.checked.insert(port, free);
+        free
+    }
+}
+
+/// Ports to try first for `service_name`: the requested one, then the ports
+/// reserved for it, lowest first, then the one it held last (its sticky port).
+pub fn preferred_ports(
+    requested: Option<u16>,
+    reservations: &HashMap<u16, Reservation>,
+    service_name: &str,
+    sticky_port: Option<u16>,
+) -> Vec<u16> {
+    let mut reserved: Vec<u16> = reservations.values().filter(|r| r.service_name == service_name).map(|r| r.port).collect();
+    reserved.sort();
+    requested.into_iter().chain(reserved).chain(sticky_port).collect()
+}
+
+fn block(start: u16, count: usize) -> Vec<u16> {
+    (0..count).map(|i| start + i as u16).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn pool(min_port: u16, max_port: u16, strategy: AllocationStrategy) -> Pool {
+        Pool { name: "test".to_string(), min_port, max_port, default_ttl: 60, strategy }
+    }
+
+    fn leases(ports: &[u16]) -> HashMap<u16, Lease> {
+        let now = Utc::now();
+        ports
+            .iter()
+            .map(|&port| {
+                let lease = Lease {
+                    port,
+                    service_name: "other".to_string(),
+                    allocated_at: now,
+                    last_heartbeat: now,
+                    ttl_seconds: 60,
+                    tags: Vec::new(),
+                    owner_pid: None,
+                    owner_start_time: None,
+                    group_id: None,
+                    role: None,
+                    pool: "test".to_string(),
+                    namespace: "default".to_string(),
+                };
+                (port, lease)
+            })
+            .collect()
+    }
+
+    fn reservations(reserved: &[(u16, &str)]) -> HashMap<u16, Reservation> {
+        reserved
+            .iter()
+            .map(|&(port, service_name)| {
+                (port, Reservation { port, service_name: service_name.to_string(), reserved_at: Utc::now() })
+            })
+            .collect()
+    }
+
+    /// Select from `pool` for the service "api", with `in_use` taken by unmanaged listeners.
+    fn select(
+        pool: &Pool,
+        leased: &[u16],
+        excluded: &[RangeInclusive<u16>],
+        reserved: &[(u16, &str)],
+        in_use: &[u16],
+        (count, contiguous, preferred): (usize, bool, &[u16]),
+    ) -> (Option<Vec<u16>>, Vec<(u16, SkipReason)>) {
+        let leases = leases(leased);
+        let reservations = reservations(reserved);
+        let mut selector =
+            PortSelector::new(pool, &leases, excluded, &reservations, "api").with_probe(PortProbe::fake(in_use));
+        let selected = selector.select(count, contiguous, preferred);
+        (selected, selector.skipped.iter().map(|s| (s.port, s.reason)).collect())
+    }
+
+    #[test]
+    fn strategies_pick_from_either_end_or_anywhere() {
+        let first = pool(9000, 9009, AllocationStrategy::FirstFree);
+        assert_eq!(select(&first, &[9000], &[], &[], &[], (2, false, &[])).0, Some(vec![9001, 9002]));
+
+        let last = pool(9000, 9009, AllocationStrategy::LastFree);
+        assert_eq!(select(&last, &[9009], &[], &[], &[], (2, false, &[])).0, Some(vec![9008, 9007]));
+
+        let random = pool(9000, 9009, AllocationStrategy::Random);
+        for _ in 0..20 {
+            let ports = select(&random, &[9003], &[], &[], &[], (1, false, &[])).0.unwrap();
+            assert!((9000..=9009).contains(&ports[0]) && ports[0] != 9003);
+        }
+        let mut all = select(&random, &[], &[], &[], &[], (10, false, &[])).0.unwrap();
+        all.sort();
+        assert_eq!(all, (9000..=9009).collect::<Vec<_>>());
+        assert_eq!(select(&random, &[], &[], &[], &[], (11, false, &[])).0, None);
+    }
+
+    #[test]
+    fn unavailable_ports_are_skipped_and_only_surprises_reported() {
+        let pool = pool(9000, 9009, AllocationStrategy::FirstFree);
+        let (selected, skipped) = select(
+            &pool,
+            &[9000],
+            &[9001..=9002],
+            &[(9003, "someone-else"), (9005, "api")],
+            &[9004],
+            (2, false, &[]),
+        );
+        // A port reserved for the service itself is fair game
+        assert_eq!(selected, Some(vec![9005, 9006]));
+        // Leased, excluded and reserved ports are expected while scanning; a listener is not
+        assert_eq!(skipped, vec![(9004, SkipReason::InUse)]);
+    }
+
+    #[test]
+    fn unavailable_preferred_ports_are_reported() {
+        let pool = pool(9000, 9009, AllocationStrategy::FirstFree);
+        let (selected, skipped) = select(
+            &pool,
+            &[9005],
+            &[9006..=9006],
+            &[(9007, "someone-else")],
+            &[9008],
+            (1, false, &[9005, 9006, 9007, 9008, 9100]),
+        );
+        assert_eq!(selected, Some(vec![9000]));
+        assert_eq!(
+            skipped,
+            vec![
+                (9005, SkipReason::Leased),
+                (9006, SkipReason::Excluded),
+                (9007, SkipReason::Reserved),
+                (9008, SkipReason::InUse),
+                (9100, SkipReason::OutOfRange),
+            ]
+        );
+    }
+
+    #[test]
+    fn contiguous_blocks_fit_around_taken_ports() {
+        let first = pool(9000, 9009, AllocationStrategy::FirstFree);
+        assert_eq!(select(&first, &[9002], &[], &[], &[], (3, true, &[])).0, Some(vec![9003, 9004, 9005]));
+        assert_eq!(select(&first, &[], &[9001..=9001], &[], &[9005], (3, true, &[])).0, Some(vec![9002, 9003, 9004]));
+        // A preferred start whose block is not free is passed over
+        assert_eq!(select(&first, &[9008], &[], &[], &[], (3, true, &[9006])).0, Some(vec![9000, 9001, 9002]));
+        assert_eq!(select(&first, &[], &[], &[], &[], (3, true, &[9006])).0, Some(vec![9006, 9007, 9008]));
+        // Blocks never run past the end of the range
+        assert_eq!(select(&first, &[], &[], &[], &[], (3, true, &[9008])).0, Some(vec![9000, 9001, 9002]));
+
+        let last = pool(9000, 9009, AllocationStrategy::LastFree);
+        assert_eq!(select(&last, &[9008], &[], &[], &[], (3, true, &[])).0, Some(vec![9005, 9006, 9007]));
+
+        assert_eq!(select(&first, &[9003, 9007], &[], &[], &[], (4, true, &[])).0, None);
+        assert_eq!(select(&first, &[], &[], &[], &[], (11, true, &[])).0, None);
+    }
+
+    #[test]
+    fn requested_then_reserved_then_sticky_ports_come_first() {
+        let reserved = reservations(&[(9007, "api"), (9003, "api"), (9004, "web")]);
+        assert_eq!(preferred_ports(Some(9001), &reserved, "api", Some(9005)), vec![9001, 9003, 9007, 9005]);
+        assert_eq!(preferred_ports(None, &reserved, "web", None), vec![9004]);
+
+        // The sticky port wins over a scan, unless it is gone
+        let pool = pool(9000, 9009, AllocationStrategy::FirstFree);
+        let sticky = preferred_ports(None, &HashMap::new(), "api", Some(9005));
+        assert_eq!(select(&pool, &[], &[], &[], &[], (1, false, &sticky)).0, Some(vec![9005]));
+        assert_eq!(select(&pool, &[9005], &[], &[], &[], (1, false, &sticky)).0, Some(vec![9000]));
+    }
+}
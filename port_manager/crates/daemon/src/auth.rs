@@ -0,0 +1,134 @@
+use crate::error::{ApiJson, ApiResult, AppError};
+use crate::namespace::{self, Identity};
+use crate::socket::Caller;
+use crate::AppState;
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use common::{ApiError, ApiToken, CreateTokenRequest, CreatedToken, ErrorCode, TokenScope};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use uuid::Uuid;
+
+/// Prefix of every token secret, so leaked ones are easy to recognise.
+const SECRET_PREFIX: &str = "pm_";
+
+/// Resolve who is calling and attach their `Identity` to the request.
+///
+/// A bearer token, if sent, decides the identity and must be valid. Without
+/// one, socket callers are identified by their uid; TCP callers are let in as
+/// the daemon's user unless `require_token` is set.
+pub async fn authenticate(State(state): State<AppState>, caller: Caller, mut request: Request, next: Next) -> Response {
+    match identify(&state, caller, request.headers()) {
+        Ok(identity) => {
+            request.extensions_mut().insert(identity);
+            next.run(request).await
+        }
+        Err(e) => AppError(e).into_response(),
+    }
+}
+
+fn identify(state: &AppState, caller: Caller, headers: &HeaderMap) -> Result<Identity, ApiError> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if let Some(secret) = bearer {
+        let store = state.store.read().unwrap();
+        return match store.tokens().get(&hash_secret(secret.trim())) {
+            Some(token) => Ok(Identity { namespace: token.namespace.clone(), scope: token.scope, caller }),
+            None => Err(ApiError::new(ErrorCode::Unauthorized, "Invalid or revoked API token")),
+        };
+    }
+
+    let config = state.config.read().unwrap();
+    if caller == Caller::Tcp && config.require_token {
+        return Err(ApiError::new(ErrorCode::Unauthorized, "An API token is required (Authorization: Bearer <token>)"));
+    }
+    Ok(Identity::from_peer(caller, &config))
+}
+
+/// Hex-encoded SHA-256 of a token secret; the only form in which secrets are stored.
+fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes()).iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn new_secret() -> String {
+    let bytes: [u8; 32] = rand::random();
+    bytes.iter().fold(SECRET_PREFIX.to_string(), |mut secret, byte| {
+        let _ = write!(secret, "{:02x}", byte);
+        secret
+    })
+}
+
+#[utoipa::path(get, path = "/v1/tokens", responses(
+    (status = 200, description = "API tokens, oldest first", body = [ApiToken]),
+    (status = 403, description = "Caller is not an admin", body = ApiError),
+))]
+pub async fn list_tokens(State(state): State<AppState>, identity: Identity) -> ApiResult<Json<Vec<ApiToken>>> {
+    identity.require(TokenScope::Admin)?;
+    let store = state.store.read().unwrap();
+    let mut tokens: Vec<ApiToken> = store.tokens().values().cloned().collect();
+    tokens.sort_by_key(|t| t.created_at);
+    Ok(Json(tokens))
+}
+
+/// Create a token. Its secret is in the response and cannot be retrieved again.
+#[utoipa::path(post, path = "/v1/tokens", request_body = CreateTokenRequest, responses(
+    (status = 200, description = "Token created", body = CreatedToken),
+    (status = 400, description = "Invalid name or namespace", body = ApiError),
+    (status = 403, description = "Caller is not an admin", body = ApiError),
+))]
+pub async fn create_token(
+    State(state): State<AppState>,
+    identity: Identity,
+    ApiJson(payload): ApiJson<CreateTokenRequest>,
+) -> ApiResult<Json<CreatedToken>> {
+    identity.require(TokenScope::Admin)?;
+    let namespace = payload.namespace.unwrap_or_else(|| identity.namespace.clone());
+    for (field, value) in [("name", &payload.name), ("namespace", &namespace)] {
+        if !namespace::valid_name(value) {
+            return Err(ApiError::invalid_request(format!("{} must be 1-128 characters", field)).into());
+        }
+    }
+
+    let token = ApiToken {
+        id: Uuid::new_v4().simple().to_string()[..12].to_string(),
+        name: payload.name,
+        namespace,
+        scope: payload.scope,
+        created_at: Utc::now(),
+    };
+    let secret = new_secret();
+    state.store.write().unwrap().insert_token(token.clone(), hash_secret(&secret))?;
+    println!("Created {} token '{}' ({}) for namespace '{}'", token.scope.as_str(), token.name, token.id, token.namespace);
+    Ok(Json(CreatedToken { token, secret }))
+}
+
+#[utoipa::path(delete, path = "/v1/tokens/{id}", params(("id" = String, Path, description = "Token id")), responses(
+    (status = 200, description = "Token revoked"),
+    (status = 403, description = "Caller is not an admin", body = ApiError),
+    (status = 404, description = "No such token", body = ApiError),
+))]
+pub async fn revoke_token(
+    State(state): State<AppState>,
+    identity: Identity,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    identity.require(TokenScope::Admin)?;
+    match state.store.write().unwrap().remove_token(&id)? {
+        Some(token) => {
+            println!("Revoked token '{}' ({})", token.name, token.id);
+            Ok(StatusCode::OK)
+        }
+        None => Err(ApiError::new(ErrorCode::NotFound, format!("No token with id '{}'", id)).into()),
+    }
+}
@@ -0,0 +1,362 @@
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_TTL: u64 = 300; // 5 minutes
+const DEFAULT_PORT_MIN: u16 = 8000;
+const DEFAULT_PORT_MAX: u16 = 9000;
+const DEFAULT_CLEANUP_INTERVAL: u64 = 10; // seconds
+const DEFAULT_CONFLICT_SCAN_INTERVAL: u64 = 30; // seconds
+const DEFAULT_HISTORY_RETENTION_DAYS: u64 = 30;
+const DEFAULT_SOCKET_MODE: u32 = 0o600; // owner only
+
+/// Where the daemon keeps its state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// SQLite database at `db_path`.
+    #[default]
+    Sqlite,
+    /// Nothing is persisted; for tests and throwaway daemons.
+    Memory,
+}
+
+/// Command line flags of the daemon. Every flag overrides the config file.
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about = "PortManager daemon", long_about = None)]
+pub struct Args {
+    /// Config file (default: ~/.portmanager/config.toml)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 127.0.0.1:3030 or [::1]:3030 (repeatable)
+    #[arg(long)]
+    pub listen: Vec<SocketAddr>,
+
+    /// Unix socket to serve the API on as well (default: $XDG_RUNTIME_DIR/portmanager.sock)
+    #[arg(long, conflicts_with = "no_socket")]
+    pub socket: Option<PathBuf>,
+
+    /// Serve the API over TCP only
+    #[arg(long)]
+    pub no_socket: bool,
+
+    /// Permissions of the Unix socket in octal, e.g. 660 to admit the owner's group
+    #[arg(long, value_parser = parse_mode)]
+    pub socket_mode: Option<u32>,
+
+    /// SQLite database file
+    #[arg(long)]
+    pub db: Option<PathBuf>,
+
+    /// Storage backend; `memory` keeps nothing across restarts
+    #[arg(long, value_enum)]
+    pub storage: Option<StorageBackend>,
+
+    /// Start of the default pool's port range
+    #[arg(long)]
+    pub port_min: Option<u16>,
+
+    /// End of the default pool's port range
+    #[arg(long)]
+    pub port_max: Option<u16>,
+
+    /// TTL in seconds for leases that do not ask for one
+    #[arg(long)]
+    pub default_ttl: Option<u64>,
+
+    /// Seconds between sweeps for expired leases
+    #[arg(long)]
+    pub cleanup_interval: Option<u64>,
+
+    /// Seconds between scans for unmanaged listeners
+    #[arg(long)]
+    pub conflict_scan_interval: Option<u64>,
+
+    /// Port or port range that is never handed out, e.g. 8080 or 8100-8110 (repeatable)
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// Days to keep lease history; 0 keeps it forever
+    #[arg(long)]
+    pub history_retention_days: Option<u64>,
+
+    /// User whose socket requests may see and release every namespace's leases (repeatable)
+    #[arg(long = "admin")]
+    pub admins: Vec<String>,
+
+    /// Ports each namespace may hold at once; 0 means no limit
+    #[arg(long)]
+    pub default_quota: Option<u32>,
+
+    /// Reject TCP requests without a valid API token
+    #[arg(long)]
+    pub require_token: bool,
+
+    /// Web origin allowed to call the API from a browser, or * for any (repeatable)
+    #[arg(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+}
+
+/// Daemon configuration, read from `config.toml` and overridden by
+/// environment variables and command line flags, in that order.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses the API and dashboard listen on; a single address is accepted too.
+    /// Changing them requires a restart.
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<SocketAddr>,
+    /// Unix socket the API is also served on; empty to disable. Changing it requires a restart.
+    pub socket_path: PathBuf,
+    /// Permissions of the Unix socket, e.g. `0o600`. Changing it requires a restart.
+    pub socket_mode: u32,
+    /// SQLite database file. Changing it requires a restart.
+    pub db_path: PathBuf,
+    /// Storage backend. Changing it requires a restart.
+    pub storage: StorageBackend,
+    /// Range of the default pool.
+    pub port_min: u16,
+    pub port_max: u16,
+    /// TTL in seconds for leases that do not ask for one.
+    pub default_ttl: u64,
+    /// Seconds between sweeps for expired leases.
+    pub cleanup_interval: u64,
+    /// Seconds between scans for unmanaged listeners.
+    pub conflict_scan_interval: u64,
+    /// Ports and port ranges that are never handed out, e.g. ["8080", "8100-8110"].
+    pub exclude: Vec<String>,
+    /// Days to keep lease history; 0 keeps it forever.
+    pub history_retention_days: u64,
+    /// Users, by login name, who may see and release leases of every namespace.
+    /// The daemon's own user and root always may.
+    pub admins: Vec<String>,
+    /// Ports each namespace may hold at once, unless `quotas` says otherwise; 0 means no limit.
+    pub default_quota: u32,
+    /// Per-namespace port limits, e.g. { alice = 50, default = 0 }.
+    pub quotas: HashMap<String, u32>,
+    /// Reject TCP requests without a valid API token. Socket callers are identified by uid instead.
+    pub require_token: bool,
+    /// Web origins allowed to call the API from a browser, besides the dashboard's own.
+    /// "*" allows any. Changing it requires a restart.
+    pub cors_origins: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 3030))],
+            socket_path: common::default_socket_path(),
+            socket_mode: DEFAULT_SOCKET_MODE,
+            db_path: crate::db::default_db_path(),
+            storage: StorageBackend::Sqlite,
+            port_min: DEFAULT_PORT_MIN,
+            port_max: DEFAULT_PORT_MAX,
+            default_ttl: DEFAULT_TTL,
+            cleanup_interval: DEFAULT_CLEANUP_INTERVAL,
+            conflict_scan_interval: DEFAULT_CONFLICT_SCAN_INTERVAL,
+            exclude: Vec::new(),
+            history_retention_days: DEFAULT_HISTORY_RETENTION_DAYS,
+            admins: Vec::new(),
+            default_quota: 0,
+            quotas: HashMap::new(),
+            require_token: false,
+            cors_origins: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Parsed form of `exclude`.
+    pub fn excluded_ranges(&self) -> Result<Vec<RangeInclusive<u16>>, String> {
+        parse_port_ranges(&self.exclude.join(","))
+    }
+
+    /// Most ports `namespace` may hold at once, or `None` if unlimited.
+    pub fn quota(&self, namespace: &str) -> Option<u32> {
+        let quota = self.quotas.get(namespace).copied().unwrap_or(self.default_quota);
+        (quota > 0).then_some(quota)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.port_min == 0 {
+            return Err("port_min must be at least 1".to_string());
+        }
+        if self.port_min > self.port_max {
+            return Err(format!("port_min ({}) is greater than port_max ({})", self.port_min, self.port_max));
+        }
+        if let Some(origin) = self.cors_origins.iter().find(|o| *o != "*" && !o.starts_with("http://") && !o.starts_with("https://")) {
+            return Err(format!("invalid CORS origin: {} (expected e.g. http://localhost:5173 or *)", origin));
+        }
+        if self.listen.is_empty() && (self.socket_path.as_os_str().is_empty() || cfg!(not(unix))) {
+            return Err("no listen address and no socket; the API would be unreachable".to_string());
+        }
+        if self.socket_mode > 0o777 {
+            return Err(format!("socket_mode {:o} is not a valid permission mode", self.socket_mode));
+        }
+        if self.cleanup_interval == 0 || self.conflict_scan_interval == 0 {
+            return Err("intervals must be at least 1 second".to_string());
+        }
+        self.excluded_ranges().map(|_| ())
+    }
+}
+
+/// Directory holding the database and config file (~/.portmanager).
+pub fn default_dir() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(".portmanager")
+}
+
+/// Path of the config file used when `--config` is not given.
+pub fn config_path(args: &Args) -> PathBuf {
+    args.config.clone().unwrap_or_else(|| default_dir().join("config.toml"))
+}
+
+/// Build the effective configuration from the config file, environment and flags.
+///
+/// A missing file is only an error if it was named explicitly with `--config`.
+pub fn load(args: &Args) -> Result<Config, String> {
+    let path = config_path(args);
+    let mut config = if path.exists() {
+        read_file(&path)?
+    } else if args.config.is_some() {
+        return Err(format!("config file not found: {}", path.display()));
+    } else {
+        Config::default()
+    };
+
+    // Environment variables predate the config file and still take precedence over it
+    if let Ok(value) = std::env::var("PM_PORT_MIN") {
+        config.port_min = value.parse().map_err(|_| "PM_PORT_MIN must be a valid port number".to_string())?;
+    }
+    if let Ok(value) = std::env::var("PM_PORT_MAX") {
+        config.port_max = value.parse().map_err(|_| "PM_PORT_MAX must be a valid port number".to_string())?;
+    }
+    if let Ok(value) = std::env::var("PM_EXCLUDE_PORTS") {
+        config.exclude = vec![value];
+    }
+
+    if !args.listen.is_empty() {
+        config.listen = args.listen.clone();
+    }
+    if let Some(socket) = &args.socket {
+        config.socket_path = socket.clone();
+    }
+    if args.no_socket {
+        config.socket_path = PathBuf::new();
+    }
+    if let Some(mode) = args.socket_mode {
+        config.socket_mode = mode;
+    }
+    if let Some(db) = &args.db {
+        config.db_path = db.clone();
+    }
+    if let Some(storage) = args.storage {
+        config.storage = storage;
+    }
+    if let Some(port_min) = args.port_min {
+        config.port_min = port_min;
+    }
+    if let Some(port_max) = args.port_max {
+        config.port_max = port_max;
+    }
+    if let Some(default_ttl) = args.default_ttl {
+        config.default_ttl = default_ttl;
+    }
+    if let Some(cleanup_interval) = args.cleanup_interval {
+        config.cleanup_interval = cleanup_interval;
+    }
+    if let Some(conflict_scan_interval) = args.conflict_scan_interval {
+        config.conflict_scan_interval = conflict_scan_interval;
+    }
+    if !args.exclude.is_empty() {
+        config.exclude = args.exclude.clone();
+    }
+    if let Some(days) = args.history_retention_days {
+        config.history_retention_days = days;
+    }
+    if !args.admins.is_empty() {
+        config.admins = args.admins.clone();
+    }
+    if let Some(quota) = args.default_quota {
+        config.default_quota = quota;
+    }
+    if args.require_token {
+        config.require_token = true;
+    }
+    if !args.cors_origins.is_empty() {
+        config.cors_origins = args.cors_origins.clone();
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+fn read_file(path: &Path) -> Result<Config, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format!("invalid config {}: {}", path.display(), e))
+}
+
+/// Accept `listen = "127.0.0.1:3030"` from older config files as well as a list.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+/// Parse an octal permission mode such as "600" or "0660".
+fn parse_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8).map_err(|_| format!("invalid octal mode: {}", value))
+}
+
+/// Parse a comma separated list of ports and port ranges, e.g. "8080,8443,8100-8110".
+pub fn parse_port_ranges(value: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (start, end) = part.split_once('-').unwrap_or((part, part));
+            let start: u16 = start.trim().parse().map_err(|_| format!("invalid port: {}", start))?;
+            let end: u16 = end.trim().parse().map_err(|_| format!("invalid port: {}", end))?;
+            if start == 0 || start > end {
+                return Err(format!("invalid port range: {}", part));
+            }
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_ranges_parse_single_ports_and_ranges() {
+        assert_eq!(parse_port_ranges("8080, 8100-8110,").unwrap(), vec![8080..=8080, 8100..=8110]);
+        assert!(parse_port_ranges("8110-8100").is_err());
+        assert!(parse_port_ranges("http").is_err());
+    }
+
+    #[test]
+    fn port_zero_is_rejected() {
+        assert!(parse_port_ranges("0").is_err());
+        assert!(parse_port_ranges("0-100").is_err());
+
+        let config = Config { port_min: 0, ..Config::default() };
+        assert!(config.validate().is_err());
+        assert!(Config::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::probe;
+use chrono::Utc;
+use common::{Conflict, Lease, Pool};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::ops::RangeInclusive;
+
+/// Find every listening socket inside any pool's range that has no lease.
+/// Excluded ports are expected to be in use by other software and are ignored.
+/// Only Linux exposes the required information; elsewhere this returns nothing.
+pub fn scan<'a>(
+    pools: impl IntoIterator<Item = &'a Pool>,
+    excluded: &[RangeInclusive<u16>],
+    leases: &HashMap<u16, Lease>,
+) -> Vec<Conflict> {
+    let ranges: Vec<_> = pools.into_iter().map(|p| p.min_port..=p.max_port).collect();
+    let listeners: Vec<probe::Listener> = probe::read_listeners()
+        .into_iter()
+        .filter(|l| ranges.iter().any(|r| r.contains(&l.port)))
+        .filter(|l| !excluded.iter().any(|r| r.contains(&l.port)) && !leases.contains_key(&l.port))
+        .collect();
+
+    if listeners.is_empty() {
+        return Vec::new();
+    }
+
+    let owners = socket_owners();
+    let now = Utc::now();
+
+    // The same port often shows up in both tcp and tcp6
+    let mut conflicts: BTreeMap<(u16, Option<u32>), Conflict> = BTreeMap::new();
+    for listener in listeners {
+        let pid = owners.get(&listener.inode).copied();
+        conflicts.entry((listener.port, pid)).or_insert_with(|| Conflict {
+            port: listener.port,
+            pid,
+            command: pid.and_then(read_cmdline),
+            detected_at: now,
+        });
+    }
+    conflicts.into_values().collect()
+}
+
+/// Map socket inodes to the PID holding them by walking /proc/<pid>/fd.
+/// Processes we are not allowed to inspect are silently skipped.
+fn socket_owners() -> HashMap<u64, u32> {
+    let mut owners = HashMap::new();
+    let Ok(procs) = fs::read_dir("/proc") else {
+        return owners;
+    };
+
+    for entry in procs.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|s| s.parse::<u32>().ok()) else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            // Socket fds link to "socket:[<inode>]"
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            let inode = target
+                .to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+                .and_then(|t| t.parse::<u64>().ok());
+            if let Some(inode) = inode {
+                owners.insert(inode, pid);
+            }
+        }
+    }
+    owners
+}
+
+/// Read a process's command line, with NUL separators replaced by spaces.
+fn read_cmdline(pid: u32) -> Option<String> {
+    let raw = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let cmdline = raw
+        .split(|b| *b == 0)
+        .filter(|part| !part.is_empty())
+        .map(|part| String::from_utf8_lossy(part).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if cmdline.is_empty() {
+        None
+    } else {
+        Some(cmdline)
+    }
+}
@@ -1,29 +1,20 @@
-use common::Lease;
+use crate::migrations;
+use common::{AllocationStrategy, ApiToken, HistoryEntry, HistoryKind, HistoryQuery, Lease, Pool, Reservation, TokenScope};
 use rusqlite::{Connection, Result, params};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use std::collections::HashMap;
 use std::path::Path;
 use std::fs;
 
-const SCHEMA: &str = r#"
-CREATE TABLE IF NOT EXISTS leases (
-    port INTEGER PRIMARY KEY,
-    service_name TEXT NOT NULL,
-    allocated_at TEXT NOT NULL,
-    last_heartbeat TEXT NOT NULL,
-    ttl_seconds INTEGER NOT NULL,
-    tags TEXT NOT NULL
-);
-"#;
-
-/// Initialize the database at the given path, creating the directory if needed.
-pub fn init_db(path: &Path) -> Result<Connection> {
+/// Open the database at the given path, creating the directory if needed,
+/// and migrate it to the current schema.
+pub fn init_db(path: &Path) -> std::result::Result<Connection, String> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent).ok();
     }
 
-    let conn = Connection::open(path)?;
-    conn.execute_batch(SCHEMA)?;
+    let mut conn = Connection::open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+    migrations::run(&mut conn, Some(path))?;
     Ok(conn)
 }
 
@@ -37,7 +28,7 @@ pub fn default_db_path() -> std::path::PathBuf {
 
 /// Load all leases from the database into a HashMap.
 pub fn load_leases(conn: &Connection) -> Result<HashMap<u16, Lease>> {
-    let mut stmt = conn.prepare("SELECT port, service_name, allocated_at, last_heartbeat, ttl_seconds, tags FROM leases")?;
+    let mut stmt = conn.prepare("SELECT port, service_name, allocated_at, last_heartbeat, ttl_seconds, tags, owner_pid, owner_start_time, group_id, role, pool, namespace FROM leases")?;
 
     let lease_iter = stmt.query_map([], |row| {
         let port: u16 = row.get(0)?;
@@ -46,6 +37,12 @@ pub fn load_leases(conn: &Connection) -> Result<HashMap<u16, Lease>> {
         let last_heartbeat_str: String = row.get(3)?;
         let ttl_seconds: u64 = row.get(4)?;
         let tags_json: String = row.get(5)?;
+        let owner_pid: Option<u32> = row.get(6)?;
+        let owner_start_time: Option<u64> = row.get(7)?;
+        let group_id: Option<String> = row.get(8)?;
+        let role: Option<String> = row.get(9)?;
+        let pool: String = row.get(10)?;
+        let namespace: String = row.get(11)?;
 
         let allocated_at = DateTime::parse_from_rfc3339(&allocated_at_str)
             .map(|dt| dt.with_timezone(&Utc))
@@ -62,14 +59,18 @@ pub fn load_leases(conn: &Connection) -> Result<HashMap<u16, Lease>> {
             last_heartbeat,
             ttl_seconds,
             tags,
+            owner_pid,
+            owner_start_time,
+            group_id,
+            role,
+            pool,
+            namespace,
         })
     })?;
 
     let mut map = HashMap::new();
-    for lease_result in lease_iter {
-        if let Ok(lease) = lease_result {
-            map.insert(lease.port, lease);
-        }
+    for lease in lease_iter.flatten() {
+        map.insert(lease.port, lease);
     }
     Ok(map)
 }
@@ -79,7 +80,7 @@ pub fn save_lease(conn: &Connection, lease: &Lease) -> Result<()> {
     let tags_json = serde_json::to_string(&lease.tags).unwrap_or_else(|_| "[]".to_string());
 
     conn.execute(
-        "INSERT OR REPLACE INTO leases (port, service_name, allocated_at, last_heartbeat, ttl_seconds, tags) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        "INSERT OR REPLACE INTO leases (port, service_name, allocated_at, last_heartbeat, ttl_seconds, tags, owner_pid, owner_start_time, group_id, role, pool, namespace) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
         params![
             lease.port,
             lease.service_name,
@@ -87,6 +88,12 @@ pub fn save_lease(conn: &Connection, lease: &Lease) -> Result<()> {
             lease.last_heartbeat.to_rfc3339(),
             lease.ttl_seconds,
             tags_json,
+            lease.owner_pid,
+            lease.owner_start_time,
+            lease.group_id,
+            lease.role,
+            lease.pool,
+            lease.namespace,
         ],
     )?;
     Ok(())
@@ -107,35 +114,199 @@ pub fn update_heartbeat(conn: &Connection, port: u16, timestamp: DateTime<Utc>)
     Ok(rows > 0)
 }
 
-/// Delete all expired leases from the database.
-pub fn delete_expired(conn: &Connection, now: DateTime<Utc>) -> Result<Vec<u16>> {
-    // First, get the expired ports
-    let mut stmt = conn.prepare(
-        "SELECT port, last_heartbeat, ttl_seconds FROM leases"
+/// Load all pool definitions stored through the API.
+pub fn load_pools(conn: &Connection) -> Result<Vec<Pool>> {
+    let mut stmt = conn.prepare("SELECT name, min_port, max_port, default_ttl, strategy FROM pools")?;
+
+    let rows = stmt.query_map([], |row| {
+        let strategy: String = row.get(4)?;
+        Ok(Pool {
+            name: row.get(0)?,
+            min_port: row.get(1)?,
+            max_port: row.get(2)?,
+            default_ttl: row.get(3)?,
+            strategy: strategy.parse().unwrap_or(AllocationStrategy::FirstFree),
+        })
+    })?;
+
+    Ok(rows.flatten().collect())
+}
+
+/// Create or update a pool definition.
+pub fn save_pool(conn: &Connection, pool: &Pool) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO pools (name, min_port, max_port, default_ttl, strategy) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![pool.name, pool.min_port, pool.max_port, pool.default_ttl, pool.strategy.as_str()],
     )?;
+    Ok(())
+}
 
-    let expired: Vec<u16> = stmt.query_map([], |row| {
-        let port: u16 = row.get(0)?;
-        let last_heartbeat_str: String = row.get(1)?;
-        let ttl_seconds: i64 = row.get(2)?;
+/// Delete a pool definition.
+pub fn delete_pool(conn: &Connection, name: &str) -> Result<bool> {
+    let rows = conn.execute("DELETE FROM pools WHERE name = ?1", params![name])?;
+    Ok(rows > 0)
+}
 
-        let last_heartbeat = DateTime::parse_from_rfc3339(&last_heartbeat_str)
+/// Load all permanent port reservations, keyed by port.
+pub fn load_reservations(conn: &Connection) -> Result<HashMap<u16, Reservation>> {
+    let mut stmt = conn.prepare("SELECT port, service_name, reserved_at FROM reservations")?;
+
+    let rows = stmt.query_map([], |row| {
+        let reserved_at_str: String = row.get(2)?;
+        let reserved_at = DateTime::parse_from_rfc3339(&reserved_at_str)
             .map(|dt| dt.with_timezone(&Utc))
-            .unwrap_or_else(|_| now);
+            .unwrap_or_else(|_| Utc::now());
+        Ok(Reservation {
+            port: row.get(0)?,
+            service_name: row.get(1)?,
+            reserved_at,
+        })
+    })?;
 
-        let expires_at = last_heartbeat + chrono::Duration::seconds(ttl_seconds);
+    Ok(rows.flatten().map(|r| (r.port, r)).collect())
+}
 
-        Ok((port, now > expires_at))
-    })?
-    .filter_map(|r| r.ok())
-    .filter(|(_, expired)| *expired)
-    .map(|(port, _)| port)
-    .collect();
+/// Save a permanent port reservation.
+pub fn save_reservation(conn: &Connection, reservation: &Reservation) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO reservations (port, service_name, reserved_at) VALUES (?1, ?2, ?3)",
+        params![reservation.port, reservation.service_name, reservation.reserved_at.to_rfc3339()],
+    )?;
+    Ok(())
+}
 
-    // Delete expired leases
-    for port in &expired {
-        conn.execute("DELETE FROM leases WHERE port = ?1", params![port])?;
-    }
+/// Delete a permanent port reservation.
+pub fn delete_reservation(conn: &Connection, port: u16) -> Result<bool> {
+    let rows = conn.execute("DELETE FROM reservations WHERE port = ?1", params![port])?;
+    Ok(rows > 0)
+}
+
+/// Load the last port each service held, keyed by namespace and service name.
+pub fn load_sticky_ports(conn: &Connection) -> Result<HashMap<(String, String), u16>> {
+    let mut stmt = conn.prepare("SELECT namespace, service_name, port FROM sticky_ports")?;
+
+    let rows = stmt.query_map([], |row| {
+        let namespace: String = row.get(0)?;
+        let service_name: String = row.get(1)?;
+        let port: u16 = row.get(2)?;
+        Ok(((namespace, service_name), port))
+    })?;
+
+    Ok(rows.flatten().collect())
+}
+
+/// Remember the port a service was given so it can get it again next time.
+pub fn save_sticky_port(
+    conn: &Connection,
+    namespace: &str,
+    service_name: &str,
+    port: u16,
+    timestamp: DateTime<Utc>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO sticky_ports (namespace, service_name, port, updated_at) VALUES (?1, ?2, ?3, ?4)",
+        params![namespace, service_name, port, timestamp.to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Load all API tokens, keyed by the hash of their secret.
+pub fn load_tokens(conn: &Connection) -> Result<HashMap<String, ApiToken>> {
+    let mut stmt = conn.prepare("SELECT id, name, secret_hash, namespace, scope, created_at FROM api_tokens")?;
+
+    let rows = stmt.query_map([], |row| {
+        let secret_hash: String = row.get(2)?;
+        let scope: String = row.get(4)?;
+        let created_at: String = row.get(5)?;
+        Ok((
+            secret_hash,
+            ApiToken {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                namespace: row.get(3)?,
+                // An unknown scope grants as little as possible
+                scope: scope.parse().unwrap_or(TokenScope::Read),
+                created_at: DateTime::parse_from_rfc3339(&created_at)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .unwrap_or_else(|_| Utc::now()),
+            },
+        ))
+    })?;
+
+    Ok(rows.flatten().collect())
+}
+
+pub fn save_token(conn: &Connection, token: &ApiToken, secret_hash: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO api_tokens (id, name, secret_hash, namespace, scope, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![token.id, token.name, secret_hash, token.namespace, token.scope.as_str(), token.created_at.to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+pub fn delete_token(conn: &Connection, id: &str) -> Result<bool> {
+    let rows = conn.execute("DELETE FROM api_tokens WHERE id = ?1", params![id])?;
+    Ok(rows > 0)
+}
+
+/// Fixed-width timestamp format used by `lease_events`.
+fn history_timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Append an entry to the lease history.
+pub fn record_history(conn: &Connection, kind: HistoryKind, lease: &Lease, reason: Option<&str>, at: DateTime<Utc>) -> Result<()> {
+    conn.execute(
+        "INSERT INTO lease_events (port, service_name, namespace, kind, reason, at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![lease.port, lease.service_name, lease.namespace, kind.as_str(), reason, history_timestamp(at)],
+    )?;
+    Ok(())
+}
+
+/// Load history entries matching the query, newest first.
+pub fn load_history(conn: &Connection, query: &HistoryQuery) -> Result<Vec<HistoryEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, port, service_name, namespace, kind, reason, at FROM lease_events
+         WHERE (?1 IS NULL OR service_name = ?1) AND (?2 IS NULL OR port = ?2) AND (?3 IS NULL OR at >= ?3)
+           AND (?5 IS NULL OR namespace = ?5)
+         ORDER BY id DESC LIMIT ?4",
+    )?;
+
+    let rows = stmt.query_map(
+        params![
+            query.service,
+            query.port,
+            query.since.map(history_timestamp),
+            query.limit.unwrap_or(100),
+            query.namespace,
+        ],
+        |row| {
+            let kind: String = row.get(4)?;
+            let at_str: String = row.get(6)?;
+            let at = DateTime::parse_from_rfc3339(&at_str)
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now());
+            Ok(HistoryEntry {
+                id: row.get(0)?,
+                port: row.get(1)?,
+                service_name: row.get(2)?,
+                namespace: row.get(3)?,
+                kind: kind.parse().unwrap_or(HistoryKind::Released),
+                reason: row.get(5)?,
+                at,
+            })
+        },
+    )?;
+
+    Ok(rows.flatten().collect())
+}
+
+/// Delete every lease, reservation and sticky port.
+pub fn clear_state(conn: &Connection) -> Result<()> {
+    conn.execute_batch("DELETE FROM leases; DELETE FROM reservations; DELETE FROM sticky_ports;")
+}
 
-    Ok(expired)
+/// Delete history entries older than `before`. Returns the number removed.
+pub fn prune_history(conn: &Connection, before: DateTime<Utc>) -> Result<usize> {
+    conn.execute("DELETE FROM lease_events WHERE at < ?1", params![history_timestamp(before)])
 }
@@ -0,0 +1,45 @@
+use axum::{
+    extract::{rejection::JsonRejection, FromRequest},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use crate::store::StoreError;
+use common::ApiError;
+
+/// Handler error that renders as the shared JSON error body.
+#[derive(Debug)]
+pub struct AppError(pub ApiError);
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.0.code.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, Json(self.0)).into_response()
+    }
+}
+
+impl From<ApiError> for AppError {
+    fn from(error: ApiError) -> Self {
+        AppError(error)
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        AppError(ApiError::invalid_request(rejection.body_text()))
+    }
+}
+
+impl From<StoreError> for AppError {
+    fn from(error: StoreError) -> Self {
+        eprintln!("Storage error: {}", error);
+        AppError(ApiError::database(error))
+    }
+}
+
+/// `Json` extractor whose rejections use the JSON error body too.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(AppError))]
+pub struct ApiJson<T>(pub T);
+
+pub type ApiResult<T> = Result<T, AppError>;
@@ -0,0 +1,154 @@
+use crate::error::AppError;
+use crate::namespace::Identity;
+use crate::AppState;
+use axum::{
+    extract::{
+        ws::{rejection::WebSocketUpgradeRejection, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::{header, HeaderMap},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use chrono::Utc;
+use common::{ApiError, Conflict, ErrorCode, EventKind, Lease, LeaseEvent};
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+/// Events a slow subscriber may fall behind by before it starts missing some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Fan-out of lease events to every connected SSE and WebSocket subscriber.
+/// Publishing never blocks; events without subscribers are dropped.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<LeaseEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, kind: EventKind, lease: &Lease) {
+        let _ = self.sender.send(LeaseEvent {
+            kind,
+            port: lease.port,
+            lease: Some(lease.clone()),
+            conflict: None,
+            at: Utc::now(),
+        });
+    }
+
+    pub fn publish_conflict(&self, conflict: &Conflict) {
+        let _ = self.sender.send(LeaseEvent {
+            kind: EventKind::Conflict,
+            port: conflict.port,
+            lease: None,
+            conflict: Some(conflict.clone()),
+            at: Utc::now(),
+        });
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<LeaseEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Query parameters of `/v1/events`. Conflicts belong to no service and are
+/// only delivered to unfiltered subscribers.
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+pub struct EventFilter {
+    /// Only events for leases of this service.
+    service: Option<String>,
+    /// Only events for leases carrying this tag.
+    tag: Option<String>,
+    /// Set by the daemon for callers who may only see their own namespace.
+    #[serde(skip)]
+    #[param(ignore)]
+    namespace: Option<String>,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &LeaseEvent) -> bool {
+        if self.service.is_none() && self.tag.is_none() && self.namespace.is_none() {
+            return true;
+        }
+        let Some(lease) = &event.lease else {
+            return false;
+        };
+        self.service.as_ref().is_none_or(|s| lease.service_name == *s)
+            && self.tag.as_ref().is_none_or(|t| lease.tags.contains(t))
+            && self.namespace.as_ref().is_none_or(|n| lease.namespace == *n)
+    }
+}
+
+/// Stream lease events as a WebSocket if the client asks for an upgrade,
+/// otherwise as Server-Sent Events. Every message is a JSON `LeaseEvent`.
+#[utoipa::path(get, path = "/v1/events", params(EventFilter), responses(
+    (status = 200, description = "Server-Sent Events stream of lease events", body = LeaseEvent, content_type = "text/event-stream"),
+    (status = 101, description = "WebSocket upgrade; each text message is a lease event"),
+    (status = 403, description = "WebSocket upgrade from a browser origin that may not call the API", body = ApiError),
+))]
+pub async fn events(
+    State(state): State<AppState>,
+    identity: Identity,
+    Query(mut filter): Query<EventFilter>,
+    headers: HeaderMap,
+    upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Response {
+    // Browsers apply no CORS to WebSockets, so any page could otherwise read the stream
+    let origin = headers.get(header::ORIGIN).map(|o| o.to_str().unwrap_or_default());
+    if upgrade.is_ok() && origin.is_some_and(|o| !state.origins.allows(o)) {
+        return AppError(ApiError::new(ErrorCode::Forbidden, "WebSocket connections from this origin are not allowed"))
+            .into_response();
+    }
+    if !identity.admin() {
+        filter.namespace = Some(identity.namespace);
+    }
+    let receiver = state.events.subscribe();
+    match upgrade {
+        Ok(ws) => ws.on_upgrade(move |socket| forward_to_socket(socket, receiver, filter)),
+        Err(_) => sse_stream(receiver, filter).into_response(),
+    }
+}
+
+fn sse_stream(
+    receiver: broadcast::Receiver<LeaseEvent>,
+    filter: EventFilter,
+) -> Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>> {
+    // Events missed by a lagging subscriber are skipped rather than ending the stream
+    let stream = BroadcastStream::new(receiver)
+        .filter_map(|event| event.ok())
+        .filter(move |event| filter.matches(event))
+        .map(|event| Event::default().event(event.kind.as_str()).json_data(&event));
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn forward_to_socket(mut socket: WebSocket, mut receiver: broadcast::Receiver<LeaseEvent>, filter: EventFilter) {
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) if filter.matches(&event) => {
+                    let Ok(text) = serde_json::to_string(&event) else {
+                        continue;
+                    };
+                    if socket.send(Message::Text(text.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            // Nothing is expected from the client; stop once it goes away
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
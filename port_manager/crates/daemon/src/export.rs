@@ -0,0 +1,241 @@
+use crate::error::{ApiJson, ApiResult};
+use crate::namespace::{self, Identity};
+use crate::store::ImportBatch;
+use crate::AppState;
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    Json,
+};
+use chrono::Utc;
+use common::{
+    validate_service_name, ApiError, EventKind, ImportConflict, ImportItem, ImportMode, ImportParams, ImportReport,
+    Lease, Pool, Reservation, StateExport, StickyPort, TokenScope, EXPORT_VERSION,
+};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// Outcome of checking one imported entry against the current state.
+enum Check {
+    Import,
+    /// Already there unchanged; nothing to do.
+    Present,
+    Conflict(String),
+}
+
+/// State an import is checked against: what is there now, or nothing when
+/// replacing it, plus the entries accepted so far.
+#[derive(Default)]
+struct Target {
+    leases: HashMap<u16, Lease>,
+    reservations: HashMap<u16, Reservation>,
+    sticky_ports: HashMap<(String, String), u16>,
+}
+
+/// Dump leases, reservations and sticky ports, sorted by port and by namespace and service name.
+#[utoipa::path(get, path = "/v1/export", responses(
+    (status = 200, description = "Current lease state", body = StateExport),
+    (status = 403, description = "Caller is not an admin", body = ApiError),
+))]
+pub async fn export_state(State(state): State<AppState>, identity: Identity) -> ApiResult<Json<StateExport>> {
+    identity.require(TokenScope::Admin)?;
+    let store = state.store.read().unwrap();
+
+    let mut leases: Vec<Lease> = store.leases().values().cloned().collect();
+    leases.sort_by_key(|l| l.port);
+    let mut reservations: Vec<Reservation> = store.reservations().values().cloned().collect();
+    reservations.sort_by_key(|r| r.port);
+    let mut sticky_ports: Vec<StickyPort> = store
+        .sticky_ports()
+        .iter()
+        .map(|((namespace, service_name), &port)| StickyPort {
+            service_name: service_name.clone(),
+            port,
+            namespace: namespace.clone(),
+        })
+        .collect();
+    sticky_ports.sort_by(|a, b| (&a.namespace, &a.service_name).cmp(&(&b.namespace, &b.service_name)));
+
+    Ok(Json(StateExport {
+        version: EXPORT_VERSION,
+        exported_at: Utc::now(),
+        leases,
+        reservations,
+        sticky_ports,
+    }))
+}
+
+/// Restore a `StateExport`. Entries that clash with existing state, excluded
+/// ports or each other are skipped and reported as conflicts.
+///
+/// Imported leases get a fresh heartbeat and lose their owner process, which
+/// means nothing on this machine; they expire unless someone heartbeats them.
+#[utoipa::path(post, path = "/v1/import", params(ImportParams), request_body = StateExport, responses(
+    (status = 200, description = "Import applied; see the report for skipped entries", body = ImportReport),
+    (status = 400, description = "Invalid document or unsupported format version", body = ApiError),
+    (status = 403, description = "Caller is not an admin", body = ApiError),
+))]
+pub async fn import_state(
+    State(state): State<AppState>,
+    identity: Identity,
+    params: Result<Query<ImportParams>, QueryRejection>,
+    ApiJson(export): ApiJson<StateExport>,
+) -> ApiResult<Json<ImportReport>> {
+    identity.require(TokenScope::Admin)?;
+    let Query(params) = params.map_err(|e| ApiError::invalid_request(e.body_text()))?;
+    if export.version > EXPORT_VERSION {
+        return Err(ApiError::invalid_request(format!(
+            "export format version {} is newer than this daemon supports ({})",
+            export.version, EXPORT_VERSION
+        ))
+        .into());
+    }
+
+    let mut store = state.store.write().unwrap();
+    let pools = state.pools.read().unwrap();
+    let excluded = state.excluded.read().unwrap();
+    let now = Utc::now();
+    let replace = params.mode == ImportMode::Replace;
+
+    // Check every entry before anything is written, against the state the import
+    // builds on plus the entries accepted so far
+    let mut target = if replace {
+        Target::default()
+    } else {
+        Target {
+            leases: store.leases().clone(),
+            reservations: store.reservations().clone(),
+            sticky_ports: store.sticky_ports().clone(),
+        }
+    };
+    let mut batch = ImportBatch { replace, ..Default::default() };
+    let mut conflicts = Vec::new();
+    let mut conflict = |item, port, service_name: &str, reason| {
+        conflicts.push(ImportConflict { item, port, service_name: service_name.to_string(), reason });
+    };
+
+    // Reservations go first so imported leases are checked against them
+    for reservation in export.reservations {
+        match check_reservation(&target, &excluded, &reservation) {
+            Check::Import => {
+                target.reservations.insert(reservation.port, reservation.clone());
+                batch.reservations.push(reservation);
+            }
+            Check::Present => {}
+            Check::Conflict(reason) => conflict(ImportItem::Reservation, reservation.port, &reservation.service_name, reason),
+        }
+    }
+
+    for mut lease in export.leases {
+        match check_lease(&target, &pools, &excluded, &lease) {
+            Check::Import => {
+                lease.last_heartbeat = now;
+                lease.owner_pid = None;
+                lease.owner_start_time = None;
+                target.leases.insert(lease.port, lease.clone());
+                batch.leases.push(lease);
+            }
+            Check::Present => {}
+            Check::Conflict(reason) => conflict(ImportItem::Lease, lease.port, &lease.service_name, reason),
+        }
+    }
+
+    for sticky in export.sticky_ports {
+        let key = (sticky.namespace.clone(), sticky.service_name.clone());
+        let check = match (validate_service_name(&sticky.service_name), target.sticky_ports.get(&key)) {
+            (Err(e), _) => Check::Conflict(e.message),
+            _ if !namespace::valid_name(&sticky.namespace) => {
+                Check::Conflict(format!("invalid namespace '{}'", sticky.namespace))
+            }
+            (Ok(()), Some(&port)) if port == sticky.port => Check::Present,
+            (Ok(()), Some(port)) => Check::Conflict(format!("service already sticks to port {}", port)),
+            (Ok(()), None) => Check::Import,
+        };
+        match check {
+            Check::Import => {
+                target.sticky_ports.insert(key, sticky.port);
+                batch.sticky_ports.push(sticky);
+            }
+            Check::Present => {}
+            Check::Conflict(reason) => conflict(ImportItem::StickyPort, sticky.port, &sticky.service_name, reason),
+        }
+    }
+
+    for lease in store.import(&batch, now)? {
+        state.events.publish(EventKind::Released, &lease);
+    }
+    for lease in &batch.leases {
+        state.events.publish(EventKind::Allocated, lease);
+    }
+
+    let report = ImportReport {
+        mode: params.mode,
+        leases: batch.leases.len(),
+        reservations: batch.reservations.len(),
+        sticky_ports: batch.sticky_ports.len(),
+        conflicts,
+    };
+    println!(
+        "Imported {} lease(s), {} reservation(s) and {} sticky port(s) ({}, {} conflict(s))",
+        report.leases,
+        report.reservations,
+        report.sticky_ports,
+        report.mode.as_str(),
+        report.conflicts.len()
+    );
+    Ok(Json(report))
+}
+
+fn check_reservation(target: &Target, excluded: &[RangeInclusive<u16>], reservation: &Reservation) -> Check {
+    if let Err(e) = validate_service_name(&reservation.service_name) {
+        return Check::Conflict(e.message);
+    }
+    if excluded.iter().any(|r| r.contains(&reservation.port)) {
+        return Check::Conflict("port is excluded".to_string());
+    }
+    match target.reservations.get(&reservation.port) {
+        Some(r) if r.service_name == reservation.service_name => return Check::Present,
+        Some(r) => return Check::Conflict(format!("port is reserved for '{}'", r.service_name)),
+        None => {}
+    }
+    match target.leases.get(&reservation.port) {
+        Some(l) if l.service_name != reservation.service_name => {
+            Check::Conflict(format!("port is leased by '{}'", l.service_name))
+        }
+        _ => Check::Import,
+    }
+}
+
+fn check_lease(
+    target: &Target,
+    pools: &HashMap<String, Pool>,
+    excluded: &[RangeInclusive<u16>],
+    lease: &Lease,
+) -> Check {
+    if let Err(e) = validate_service_name(&lease.service_name) {
+        return Check::Conflict(e.message);
+    }
+    if !namespace::valid_name(&lease.namespace) {
+        return Check::Conflict(format!("invalid namespace '{}'", lease.namespace));
+    }
+    match pools.get(&lease.pool) {
+        None => return Check::Conflict(format!("pool '{}' does not exist", lease.pool)),
+        Some(pool) if !(pool.min_port..=pool.max_port).contains(&lease.port) => {
+            return Check::Conflict(format!("port is outside pool '{}' ({}-{})", pool.name, pool.min_port, pool.max_port));
+        }
+        Some(_) => {}
+    }
+    if excluded.iter().any(|r| r.contains(&lease.port)) {
+        return Check::Conflict("port is excluded".to_string());
+    }
+    match target.leases.get(&lease.port) {
+        Some(l) if l.service_name == lease.service_name => return Check::Present,
+        Some(l) => return Check::Conflict(format!("port is leased by '{}'", l.service_name)),
+        None => {}
+    }
+    match target.reservations.get(&lease.port) {
+        Some(r) if r.service_name != lease.service_name => {
+            Check::Conflict(format!("port is reserved for '{}'", r.service_name))
+        }
+        _ => Check::Import,
+    }
+}
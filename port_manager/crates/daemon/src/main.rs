@@ -1,27 +1,59 @@
+mod alloc;
+mod auth;
+mod config;
+mod conflicts;
 mod db;
+mod error;
+mod events;
+mod export;
+mod migrations;
+mod namespace;
+mod openapi;
+mod origin;
+mod probe;
+mod process;
+mod socket;
+mod store;
 
 use axum::{
     body::Body,
-    extract::{Path, Query, State, Json},
-    http::{header, StatusCode},
+    extract::{rejection::QueryRejection, Path, Query, Request, State, Json},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
     response::{Html, IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
-use common::{AllocateRequest, AllocateResponse, ReleaseRequest, HeartbeatRequest, Lease, LookupResponse};
+use alloc::PortSelector;
+use error::{ApiJson, ApiResult, AppError};
+use events::EventBus;
+use namespace::Identity;
+use origin::Origins;
+use socket::Caller;
+use store::{LeaseStore, MemoryStore, SqliteStore};
+use clap::Parser;
+use config::{Config, StorageBackend};
+use common::{
+    validate_service_name, AllocateRequest, AllocateResponse, AllocationStrategy, ApiError, Conflict, ConflictsParams, ErrorCode, EventKind,
+    HistoryEntry, HistoryKind, HistoryQuery, TokenScope,
+    ReleaseRequest, HeartbeatRequest, Lease, LookupResponse, Pool, PoolSpec, Reservation, ReserveRequest, DEFAULT_POOL,
+};
 use rust_embed::Embed;
-use rusqlite::Connection;
 use std::{
     collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, RwLock, Mutex},
+    ops::RangeInclusive,
+    sync::{Arc, RwLock},
     time::Duration,
 };
 use tokio::time;
-use tower_http::cors::CorsLayer;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use chrono::Utc;
+use uuid::Uuid;
 
-const DEFAULT_TTL: u64 = 300; // 5 minutes
+const MAX_BLOCK_SIZE: usize = 64; // ports per group allocation
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+const MAX_HISTORY_LIMIT: u32 = 10_000;
 
 #[derive(Embed)]
 #[folder = "dashboard/"]
@@ -29,116 +61,374 @@ struct DashboardAssets;
 
 #[derive(Clone)]
 struct AppState {
-    leases: Arc<RwLock<HashMap<u16, Lease>>>,
-    db: Arc<Mutex<Connection>>,
-    min_port: u16,
-    max_port: u16,
+    /// Leases, sticky ports and reservations live only here.
+    store: Arc<RwLock<Box<dyn LeaseStore>>>,
+    conflicts: Arc<RwLock<Vec<Conflict>>>,
+    pools: Arc<RwLock<HashMap<String, Pool>>>,
+    excluded: Arc<RwLock<Vec<RangeInclusive<u16>>>>,
+    config: Arc<RwLock<Config>>,
+    events: EventBus,
+    origins: Origins,
 }
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    // Initialize database
-    let db_path = db::default_db_path();
-    println!("Using database: {}", db_path.display());
+    let args = config::Args::parse();
+    let config = match config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let config_path = config::config_path(&args);
+    if config_path.exists() {
+        println!("Using config: {}", config_path.display());
+    }
 
-    let conn = db::init_db(&db_path).expect("Failed to initialize database");
+    let store: Box<dyn LeaseStore> = match config.storage {
+        StorageBackend::Sqlite => {
+            println!("Using database: {}", config.db_path.display());
+            match SqliteStore::open(&config.db_path) {
+                Ok(store) => Box::new(store),
+                Err(e) => {
+                    eprintln!("Failed to initialize database: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        StorageBackend::Memory => {
+            println!("Using in-memory storage; nothing is kept after exit");
+            Box::new(MemoryStore::new())
+        }
+    };
 
-    // Load existing leases from database
-    let existing_leases = db::load_leases(&conn).unwrap_or_default();
-    let lease_count = existing_leases.len();
+    let lease_count = store.leases().len();
     if lease_count > 0 {
         println!("Loaded {} existing lease(s) from database", lease_count);
     }
 
-    // Clean up expired leases immediately
-    match db::delete_expired(&conn, Utc::now()) {
-        Ok(expired) => {
-            if !expired.is_empty() {
-                println!("Cleaned up {} expired lease(s) on startup", expired.len());
-            }
-        },
-        Err(e) => eprintln!("Failed to cleanup expired leases on startup: {}", e),
-    }
-
-    // Read configuration from environment
-    let min_port: u16 = std::env::var("PM_PORT_MIN")
-        .unwrap_or_else(|_| "8000".to_string())
-        .parse()
-        .expect("PM_PORT_MIN must be a valid port number");
+    println!("Port Range Configuration: {}-{}", config.port_min, config.port_max);
 
-    let max_port: u16 = std::env::var("PM_PORT_MAX")
-        .unwrap_or_else(|_| "9000".to_string())
-        .parse()
-        .expect("PM_PORT_MAX must be a valid port number");
+    // Validated by config::load
+    let excluded = config.excluded_ranges().unwrap_or_default();
+    if !config.exclude.is_empty() {
+        println!("Excluded ports: {}", config.exclude.join(", "));
+    }
 
-    println!("Port Range Configuration: {}-{}", min_port, max_port);
+    // The default pool comes from the configuration, named pools from the store
+    let mut pools: HashMap<String, Pool> = store.pools()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|pool| (pool.name.clone(), pool))
+        .collect();
+    pools.insert(DEFAULT_POOL.to_string(), default_pool(&config));
+    for pool in pools.values().filter(|p| p.name != DEFAULT_POOL) {
+        println!("Pool '{}': {}-{} ({})", pool.name, pool.min_port, pool.max_port, pool.strategy.as_str());
+    }
 
     let state = AppState {
-        leases: Arc::new(RwLock::new(existing_leases)),
-        db: Arc::new(Mutex::new(conn)),
-        min_port,
-        max_port,
+        store: Arc::new(RwLock::new(store)),
+        conflicts: Arc::new(RwLock::new(Vec::new())),
+        pools: Arc::new(RwLock::new(pools)),
+        excluded: Arc::new(RwLock::new(excluded)),
+        config: Arc::new(RwLock::new(config.clone())),
+        events: EventBus::new(),
+        origins: Origins::new(&config),
     };
 
+    // Clean up leases that expired while the daemon was down
+    let expired = release_stale_leases(&state);
+    if expired > 0 {
+        println!("Cleaned up {} expired lease(s) on startup", expired);
+    }
+
     // Background cleaner
     let cleaner_state = state.clone();
     tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(10));
+        let mut last_prune: Option<time::Instant> = None;
         loop {
-            interval.tick().await;
+            let interval = cleaner_state.config.read().unwrap().cleanup_interval;
+            time::sleep(Duration::from_secs(interval)).await;
             let now = Utc::now();
 
-            // Get expired ports from memory
-            let expired: Vec<u16> = {
-                let leases = cleaner_state.leases.read().unwrap();
-                leases
-                    .iter()
-                    .filter(|(_, lease)| {
-                        let expires_at = lease.last_heartbeat + chrono::Duration::seconds(lease.ttl_seconds as i64);
-                        now > expires_at
-                    })
-                    .map(|(port, _)| *port)
-                    .collect()
-            };
+            // Drop old history now and then; it is only appended to otherwise
+            if last_prune.is_none_or(|t| t.elapsed() >= HISTORY_PRUNE_INTERVAL) {
+                last_prune = Some(time::Instant::now());
+                let days = cleaner_state.config.read().unwrap().history_retention_days;
+                if days > 0 {
+                    let cutoff = now - chrono::Duration::days(days as i64);
+                    match cleaner_state.store.write().unwrap().prune_history(cutoff) {
+                        Ok(0) => {}
+                        Ok(pruned) => println!("Pruned {} history entries older than {} days", pruned, days),
+                        Err(e) => eprintln!("Failed to prune history: {}", e),
+                    }
+                }
+            }
 
-            // Remove from both memory and database
-            if !expired.is_empty() {
-                let mut leases = cleaner_state.leases.write().unwrap();
-                let db = cleaner_state.db.lock().unwrap();
+            release_stale_leases(&cleaner_state);
+        }
+    });
 
-                for port in expired {
-                    println!("Releasing expired port: {}", port);
-                    leases.remove(&port);
-                    let _ = db::delete_lease(&db, port);
+    // Background conflict scanner
+    let scanner_state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            let previous: Vec<u16> = scanner_state.conflicts.read().unwrap().iter().map(|c| c.port).collect();
+            for conflict in refresh_conflicts(&scanner_state) {
+                if !previous.contains(&conflict.port) {
+                    println!(
+                        "Unmanaged listener on port {} (pid: {}, command: {})",
+                        conflict.port,
+                        conflict.pid.map(|p| p.to_string()).unwrap_or_else(|| "unknown".to_string()),
+                        conflict.command.as_deref().unwrap_or("unknown"),
+                    );
                 }
             }
+            let interval = scanner_state.config.read().unwrap().conflict_scan_interval;
+            time::sleep(Duration::from_secs(interval)).await;
         }
     });
 
+    // Reload the config file on SIGHUP
+    #[cfg(unix)]
+    {
+        let reload_state = state.clone();
+        tokio::spawn(async move {
+            let mut hangup = signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler");
+            while hangup.recv().await.is_some() {
+                println!("Received SIGHUP, reloading {}", config::config_path(&args).display());
+                match config::load(&args).and_then(|new_config| apply_config(&reload_state, new_config)) {
+                    Ok(()) => println!("Configuration reloaded"),
+                    Err(e) => eprintln!("Configuration not reloaded: {}", e),
+                }
+            }
+        });
+    }
+
+    let cors = state.origins.cors_layer();
+    let host_check = state.origins.clone();
+
     // API routes
-    let api_routes = Router::new()
+    let baseline_routes = Router::new()
         .route("/alloc", post(allocate_port))
         .route("/release", post(release_port))
         .route("/heartbeat", post(heartbeat))
         .route("/list", get(list_leases))
-        .route("/lookup", get(lookup_service))
-        .with_state(state);
+        .route("/lookup", get(lookup_service));
+
+    let api_routes = baseline_routes
+        .clone()
+        .route("/conflicts", get(list_conflicts))
+        .route("/pools", get(list_pools))
+        .route("/pools/{name}", put(put_pool).delete(delete_pool))
+        .route("/reservations", get(list_reservations).post(reserve_port))
+        .route("/reservations/{port}", delete(unreserve_port))
+        .route("/events", get(events::events))
+        .route("/history", get(list_history))
+        .route("/export", get(export::export_state))
+        .route("/import", post(export::import_state))
+        .route("/tokens", get(auth::list_tokens).post(auth::create_token))
+        .route("/tokens/{id}", delete(auth::revoke_token))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::authenticate))
+        .with_state(state.clone());
+
+    let v1_routes = api_routes
+        .route("/openapi.json", get(openapi::openapi_json))
+        .fallback(api_not_found);
+
+    // The endpoints that predate /v1 are kept as deprecated unversioned aliases
+    let legacy_routes = baseline_routes
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::authenticate))
+        .with_state(state)
+        .layer(middleware::from_fn(deprecated_alias));
 
     // Main app: API + Dashboard
     let app = Router::new()
-        .merge(api_routes)
+        .nest("/v1", v1_routes)
+        .merge(legacy_routes)
         .route("/", get(index_handler))
         .route("/assets/{*path}", get(static_handler))
-        .fallback(get(index_handler))  // SPA fallback
-        .layer(CorsLayer::permissive());
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3030));
-    println!("Listening on http://{}", addr);
-    println!("Dashboard available at http://{}/", addr);
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+        .fallback(spa_fallback)
+        .layer(middleware::from_fn_with_state(host_check, origin::check_host))
+        .layer(cors);
+
+    let mut servers = tokio::task::JoinSet::new();
+    for &addr in &config.listen {
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        };
+        println!("Listening on http://{}", addr);
+        if !addr.ip().is_loopback() && !config.require_token {
+            println!("Warning: {} is reachable from other machines, which can allocate and release ports without a token (see --require-token)", addr);
+        }
+        let app = app.clone();
+        servers.spawn(async move { axum::serve(listener, app).await });
+    }
+    if let Some(addr) = config.listen.first() {
+        println!("Dashboard available at http://{}/", addr);
+    }
+
+    // The same API on a Unix socket, where file permissions decide who may connect
+    // and each caller's uid and pid are known
+    #[cfg(unix)]
+    if !config.socket_path.as_os_str().is_empty() {
+        match socket::bind(&config.socket_path, config.socket_mode) {
+            Ok(unix_listener) => {
+                println!("Listening on unix:{} (mode {:o})", config.socket_path.display(), config.socket_mode);
+                let app = app.clone();
+                servers.spawn(async move {
+                    axum::serve(unix_listener, app.into_make_service_with_connect_info::<Caller>()).await
+                });
+            }
+            Err(e) => {
+                eprintln!("Failed to open API socket: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Listeners only stop on error, which takes the whole daemon down
+    if let Some(result) = servers.join_next().await {
+        result.unwrap().unwrap();
+    }
+}
+
+/// Release leases whose TTL ran out or whose owner process has exited.
+/// Returns how many were released.
+fn release_stale_leases(state: &AppState) -> usize {
+    let now = Utc::now();
+
+    // Find candidates under the read lock; checking owner processes reads /proc
+    let (expired, orphaned): (Vec<u16>, Vec<(u16, u32)>) = {
+        let store = state.store.read().unwrap();
+        let expired: Vec<u16> = store
+            .leases()
+            .values()
+            .filter(|lease| {
+                let expires_at = lease.last_heartbeat + chrono::Duration::seconds(lease.ttl_seconds as i64);
+                now > expires_at
+            })
+            .map(|lease| lease.port)
+            .collect();
+        // Ports whose owner process has exited, whatever TTL remains
+        let orphaned = store
+            .leases()
+            .values()
+            .filter(|lease| !expired.contains(&lease.port))
+            .filter_map(|lease| {
+                let pid = lease.owner_pid?;
+                (!process::is_alive(pid, lease.owner_start_time)).then_some((lease.port, pid))
+            })
+            .collect();
+        (expired, orphaned)
+    };
+
+    if expired.is_empty() && orphaned.is_empty() {
+        return 0;
+    }
+
+    let mut store = state.store.write().unwrap();
+    let mut released = 0;
+    for port in expired {
+        println!("Releasing expired port: {}", port);
+        let Some(ttl) = store.leases().get(&port).map(|l| l.ttl_seconds) else {
+            continue;
+        };
+        let reason = format!("no heartbeat within TTL of {}s", ttl);
+        match store.remove_lease(port, HistoryKind::Expired, &reason, now) {
+            Ok(Some(lease)) => {
+                state.events.publish(EventKind::Expired, &lease);
+                released += 1;
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to release expired port {}: {}", port, e),
+        }
+    }
+    for (port, pid) in orphaned {
+        println!("Releasing port {}: owner process {} exited", port, pid);
+        let reason = format!("owner process {} exited", pid);
+        match store.remove_lease(port, HistoryKind::Released, &reason, now) {
+            Ok(Some(lease)) => {
+                state.events.publish(EventKind::Released, &lease);
+                released += 1;
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to release port {}: {}", port, e),
+        }
+    }
+    released
+}
+
+fn default_pool(config: &Config) -> Pool {
+    Pool {
+        name: DEFAULT_POOL.to_string(),
+        min_port: config.port_min,
+        max_port: config.port_max,
+        default_ttl: config.default_ttl,
+        strategy: AllocationStrategy::FirstFree,
+    }
+}
+
+/// Apply a reloaded configuration to the running daemon. Existing leases are kept.
+///
+/// A range that no longer covers active leases of the default pool is rejected
+/// as a whole. Settings that only take effect at startup are logged and ignored.
+fn apply_config(state: &AppState, new_config: Config) -> Result<(), String> {
+    let excluded = new_config.excluded_ranges()?;
+    let store = state.store.read().unwrap();
+    let mut config = state.config.write().unwrap();
+
+    let mut orphaned: Vec<u16> = store
+        .leases()
+        .values()
+        .filter(|l| l.pool == DEFAULT_POOL && !(new_config.port_min..=new_config.port_max).contains(&l.port))
+        .map(|l| l.port)
+        .collect();
+    if !orphaned.is_empty() {
+        orphaned.sort();
+        return Err(format!(
+            "port range {}-{} would orphan active lease(s) on port(s) {:?}",
+            new_config.port_min, new_config.port_max, orphaned
+        ));
+    }
+
+    // These are only read at startup; keep running with the old values
+    let restart_only = [
+        ("listen", new_config.listen != config.listen),
+        ("socket_path", new_config.socket_path != config.socket_path),
+        ("socket_mode", new_config.socket_mode != config.socket_mode),
+        ("storage", new_config.storage != config.storage),
+        ("db_path", new_config.db_path != config.db_path),
+        ("cors_origins", new_config.cors_origins != config.cors_origins),
+    ];
+    for (field, _) in restart_only.iter().filter(|(_, changed)| *changed) {
+        println!("Warning: ignoring changed {} until restart", field);
+    }
+
+    // Newly excluded ports keep their lease until it is released, but are not handed out again
+    for lease in store.leases().values().filter(|l| excluded.iter().any(|r| r.contains(&l.port))) {
+        println!("Port {} is now excluded but still leased by '{}'", lease.port, lease.service_name);
+    }
+
+    state.pools.write().unwrap().insert(DEFAULT_POOL.to_string(), default_pool(&new_config));
+    *state.excluded.write().unwrap() = excluded;
+    *config = Config {
+        listen: config.listen.clone(),
+        socket_path: config.socket_path.clone(),
+        socket_mode: config.socket_mode,
+        db_path: config.db_path.clone(),
+        storage: config.storage,
+        cors_origins: config.cors_origins.clone(),
+        ..new_config
+    };
+    Ok(())
 }
 
 // Serve index.html
@@ -149,6 +439,35 @@ async fn index_handler() -> impl IntoResponse {
     }
 }
 
+// Unknown paths get the dashboard only when a browser asks for a page;
+// API clients get a JSON 404 instead of HTML with a 200
+async fn spa_fallback(method: Method, headers: HeaderMap) -> Response {
+    let wants_html = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"));
+    if method == Method::GET && wants_html {
+        index_handler().await.into_response()
+    } else {
+        api_not_found().await.into_response()
+    }
+}
+
+async fn api_not_found() -> AppError {
+    ApiError::new(ErrorCode::NotFound, "No such API endpoint").into()
+}
+
+// Mark responses of the unversioned aliases as deprecated and point at their /v1 successor
+async fn deprecated_alias(request: Request, next: Next) -> Response {
+    let successor = format!("</v1{}>; rel=\"successor-version\"", request.uri().path());
+    let mut response = next.run(request).await;
+    response.headers_mut().insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        response.headers_mut().insert(header::LINK, link);
+    }
+    response
+}
+
 // Serve static assets (JS, CSS, images)
 async fn static_handler(Path(path): Path<String>) -> impl IntoResponse {
     let path = format!("assets/{}", path);
@@ -169,103 +488,475 @@ async fn static_handler(Path(path): Path<String>) -> impl IntoResponse {
     }
 }
 
+#[utoipa::path(post, path = "/v1/alloc", request_body = AllocateRequest, responses(
+    (status = 200, description = "Ports allocated", body = AllocateResponse),
+    (status = 400, description = "Invalid request", body = ApiError),
+    (status = 403, description = "Token lacks the allocate scope", body = ApiError),
+    (status = 404, description = "Unknown pool", body = ApiError),
+    (status = 429, description = "Namespace quota exceeded", body = ApiError),
+    (status = 503, description = "No free port in the pool", body = ApiError),
+))]
 async fn allocate_port(
     State(state): State<AppState>,
-    Json(payload): Json<AllocateRequest>,
-) -> Result<Json<AllocateResponse>, StatusCode> {
-    let mut leases = state.leases.write().unwrap();
-
-    // Find free port
-    let mut selected_port = None;
-    for port in state.min_port..=state.max_port {
-        if !leases.contains_key(&port) {
-            selected_port = Some(port);
-            break;
+    identity: Identity,
+    ApiJson(payload): ApiJson<AllocateRequest>,
+) -> ApiResult<Json<AllocateResponse>> {
+    identity.require(TokenScope::Allocate)?;
+    validate_service_name(&payload.service_name)?;
+
+    let roles = payload.roles.clone().unwrap_or_default();
+    let count = match (payload.count, roles.len()) {
+        (Some(0), _) => return Err(ApiError::invalid_request("count must be at least 1").into()),
+        (Some(n), r) if r > 0 && n as usize != r => {
+            return Err(ApiError::invalid_request(format!("count ({}) does not match the number of roles ({})", n, r)).into());
         }
+        (Some(n), _) => n as usize,
+        (None, 0) => 1,
+        (None, r) => r,
+    };
+    if count > MAX_BLOCK_SIZE {
+        return Err(ApiError::invalid_request(format!("at most {} ports can be allocated at once", MAX_BLOCK_SIZE)).into());
+    }
+    if let Some(duplicate) = roles.iter().enumerate().find(|(i, r)| roles[..*i].contains(r)).map(|(_, r)| r) {
+        return Err(ApiError::invalid_request(format!("duplicate role '{}'", duplicate)).into());
     }
 
-    match selected_port {
-        Some(port) => {
+    let pool_name = payload.pool.clone().unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.read().unwrap().get(&pool_name).cloned()
+        .ok_or_else(|| ApiError::pool_not_found(&pool_name))?;
+    let quota = state.config.read().unwrap().quota(&identity.namespace);
+
+    let mut store = state.store.write().unwrap();
+    if let Some(quota) = quota {
+        let in_use = store.leases().values().filter(|l| l.namespace == identity.namespace).count();
+        if in_use + count > quota as usize {
+            return Err(ApiError::new(
+                ErrorCode::QuotaExceeded,
+                format!("Namespace '{}' may hold {} port(s) and already holds {}", identity.namespace, quota, in_use),
+            )
+            .with_details(serde_json::json!({
+                "namespace": identity.namespace,
+                "quota": quota,
+                "in_use": in_use,
+                "requested": count,
+            }))
+            .into());
+        }
+    }
+    // Try the requested port, then the service's reserved and previous ports, then scan for free ones.
+    // The whole block is selected and stored under the write lock, so it is all-or-nothing.
+    let sticky_port = store.sticky_port(&identity.namespace, &payload.service_name);
+    let preferred = alloc::preferred_ports(payload.preferred_port, store.reservations(), &payload.service_name, sticky_port);
+    let excluded = state.excluded.read().unwrap();
+    let mut selector = PortSelector::new(&pool, store.leases(), &excluded, store.reservations(), &payload.service_name);
+    let selected = selector.select(count, payload.contiguous, &preferred);
+    let skipped = selector.skipped;
+    drop(excluded);
+
+    match selected {
+        Some(ports) => {
             let now = Utc::now();
-            let lease = Lease {
+            let owner_start_time = payload.owner_start_time
+                .or_else(|| payload.owner_pid.and_then(process::start_time));
+            let group_id = (count > 1 || !roles.is_empty()).then(|| Uuid::new_v4().to_string());
+            let tags = payload.tags.unwrap_or_default();
+
+            let new_leases: Vec<Lease> = ports
+                .iter()
+                .enumerate()
+                .map(|(i, &port)| Lease {
+                    port,
+                    service_name: payload.service_name.clone(),
+                    allocated_at: now,
+                    last_heartbeat: now,
+                    ttl_seconds: payload.ttl_seconds.unwrap_or(pool.default_ttl),
+                    tags: tags.clone(),
+                    owner_pid: payload.owner_pid,
+                    owner_start_time,
+                    group_id: group_id.clone(),
+                    role: roles.get(i).cloned(),
+                    pool: pool.name.clone(),
+                    namespace: identity.namespace.clone(),
+                })
+                .collect();
+            let port = ports[0];
+
+            store.insert_leases(&new_leases)?;
+            if let Err(e) = store.set_sticky_port(&identity.namespace, &payload.service_name, port, now) {
+                eprintln!("Failed to save sticky port: {}", e);
+            }
+            for lease in &new_leases {
+                state.events.publish(EventKind::Allocated, lease);
+            }
+            Ok(Json(AllocateResponse {
                 port,
-                service_name: payload.service_name,
-                allocated_at: now,
-                last_heartbeat: now,
-                ttl_seconds: payload.ttl_seconds.unwrap_or(DEFAULT_TTL),
-                tags: payload.tags.unwrap_or_default(),
+                lease: new_leases[0].clone(),
+                skipped,
+                group_id,
+                leases: new_leases,
+            }))
+        }
+        None => {
+            let message = if count == 1 {
+                format!("No free ports in pool '{}' ({}-{})", pool.name, pool.min_port, pool.max_port)
+            } else {
+                format!(
+                    "No room for {}{} ports in pool '{}' ({}-{})",
+                    count,
+                    if payload.contiguous { " contiguous" } else { "" },
+                    pool.name,
+                    pool.min_port,
+                    pool.max_port
+                )
             };
+            Err(ApiError::new(ErrorCode::PoolExhausted, message)
+                .with_details(serde_json::json!({
+                    "pool": pool.name,
+                    "requested": count,
+                    "contiguous": payload.contiguous,
+                    "skipped": skipped,
+                }))
+                .into())
+        }
+    }
+}
 
-            // Save to database first
-            {
-                let db = state.db.lock().unwrap();
-                if let Err(e) = db::save_lease(&db, &lease) {
-                    eprintln!("Failed to save lease to database: {}", e);
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                }
+/// Resolve a request that names either a single port or a whole group to the ports it covers.
+fn target_ports(
+    leases: &HashMap<u16, Lease>,
+    port: Option<u16>,
+    group_id: Option<&str>,
+) -> Result<Vec<u16>, ApiError> {
+    match (port, group_id) {
+        (_, Some(group_id)) => {
+            let ports: Vec<u16> = leases
+                .values()
+                .filter(|l| l.group_id.as_deref() == Some(group_id))
+                .map(|l| l.port)
+                .collect();
+            if ports.is_empty() {
+                Err(ApiError::group_not_found(group_id))
+            } else {
+                Ok(ports)
             }
-
-            // Then update memory
-            leases.insert(port, lease.clone());
-            Ok(Json(AllocateResponse { port, lease }))
         }
-        None => Err(StatusCode::SERVICE_UNAVAILABLE),
+        (Some(port), None) if leases.contains_key(&port) => Ok(vec![port]),
+        (Some(port), None) => Err(ApiError::port_not_leased(port)),
+        (None, None) => Err(ApiError::invalid_request("either port or group_id is required")),
     }
 }
 
+/// Fail unless the caller may act on the leases of all `ports`.
+fn check_access(identity: &Identity, leases: &HashMap<u16, Lease>, ports: &[u16]) -> Result<(), ApiError> {
+    match ports.iter().filter_map(|port| leases.get(port)).find(|l| !identity.can_access(l)) {
+        Some(lease) => Err(ApiError::forbidden(format!("Port {} belongs to namespace '{}'", lease.port, lease.namespace))
+            .with_details(serde_json::json!({ "port": lease.port, "namespace": lease.namespace }))),
+        None => Ok(()),
+    }
+}
+
+#[utoipa::path(post, path = "/v1/release", request_body = ReleaseRequest, responses(
+    (status = 200, description = "Lease released"),
+    (status = 403, description = "Lease belongs to another namespace", body = ApiError),
+    (status = 404, description = "Port or group not leased", body = ApiError),
+))]
 async fn release_port(
     State(state): State<AppState>,
-    Json(payload): Json<ReleaseRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut leases = state.leases.write().unwrap();
-
-    if leases.remove(&payload.port).is_some() {
-        // Also delete from database
-        let db = state.db.lock().unwrap();
-        let _ = db::delete_lease(&db, payload.port);
-        Ok(StatusCode::OK)
-    } else {
-        Err(StatusCode::NOT_FOUND)
+    identity: Identity,
+    ApiJson(payload): ApiJson<ReleaseRequest>,
+) -> ApiResult<StatusCode> {
+    identity.require(TokenScope::Allocate)?;
+    let mut store = state.store.write().unwrap();
+    let ports = target_ports(store.leases(), payload.port, payload.group_id.as_deref())?;
+    check_access(&identity, store.leases(), &ports)?;
+
+    let reason = format!("released by {}", identity.caller);
+    for port in ports {
+        if let Some(lease) = store.remove_lease(port, HistoryKind::Released, &reason, Utc::now())? {
+            state.events.publish(EventKind::Released, &lease);
+        }
     }
+    Ok(StatusCode::OK)
 }
 
+#[utoipa::path(post, path = "/v1/heartbeat", request_body = HeartbeatRequest, responses(
+    (status = 200, description = "Lease renewed"),
+    (status = 403, description = "Lease belongs to another namespace", body = ApiError),
+    (status = 404, description = "Port or group not leased", body = ApiError),
+))]
 async fn heartbeat(
     State(state): State<AppState>,
-    Json(payload): Json<HeartbeatRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut leases = state.leases.write().unwrap();
+    identity: Identity,
+    ApiJson(payload): ApiJson<HeartbeatRequest>,
+) -> ApiResult<StatusCode> {
+    identity.require(TokenScope::Allocate)?;
+    let mut store = state.store.write().unwrap();
+    let ports = target_ports(store.leases(), payload.port, payload.group_id.as_deref())?;
+    check_access(&identity, store.leases(), &ports)?;
+
+    let now = Utc::now();
+    for port in ports {
+        let Some(previous) = store.leases().get(&port).map(|l| l.last_heartbeat) else {
+            continue;
+        };
+        let Some(lease) = store.touch_lease(port, now)?.cloned() else {
+            continue;
+        };
+
+        // A late heartbeat means the lease came close to expiring
+        let gap = (now - previous).num_seconds();
+        if gap > 0 && gap as u64 > lease.ttl_seconds / 2 {
+            let reason = format!("no heartbeat for {}s of {}s TTL", gap, lease.ttl_seconds);
+            if let Err(e) = store.record_history(HistoryKind::HeartbeatGap, &lease, Some(&reason), now) {
+                eprintln!("Failed to record heartbeat gap of port {}: {}", port, e);
+            }
+        }
+        state.events.publish(EventKind::Heartbeat, &lease);
+    }
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(get, path = "/v1/list", params(
+    ("pool" = Option<String>, Query, description = "Only leases from this pool"),
+    ("namespace" = Option<String>, Query, description = "Only leases from this namespace; other namespaces need admin rights"),
+), responses(
+    (status = 200, description = "Active leases visible to the caller", body = [Lease]),
+    (status = 403, description = "Namespace not visible to the caller", body = ApiError),
+))]
+async fn list_leases(
+    State(state): State<AppState>,
+    identity: Identity,
+    Query(params): Query<HashMap<String, String>>,
+) -> ApiResult<Json<Vec<Lease>>> {
+    let pool = params.get("pool");
+    let namespace = identity.visible_namespace(params.get("namespace").map(String::as_str))?;
+
+    let store = state.store.read().unwrap();
+    Ok(Json(
+        store
+            .leases()
+            .values()
+            .filter(|l| pool.is_none_or(|p| l.pool == *p))
+            .filter(|l| namespace.is_none_or(|n| l.namespace == *n))
+            .cloned()
+            .collect(),
+    ))
+}
+
+#[utoipa::path(get, path = "/v1/history", params(HistoryQuery), responses(
+    (status = 200, description = "Lease history visible to the caller, newest first", body = [HistoryEntry]),
+    (status = 400, description = "Invalid query", body = ApiError),
+    (status = 403, description = "Namespace not visible to the caller", body = ApiError),
+))]
+async fn list_history(
+    State(state): State<AppState>,
+    identity: Identity,
+    query: Result<Query<HistoryQuery>, QueryRejection>,
+) -> ApiResult<Json<Vec<HistoryEntry>>> {
+    let Query(mut query) = query.map_err(|e| ApiError::invalid_request(e.body_text()))?;
+    query.namespace = identity.visible_namespace(query.namespace.as_deref())?.map(str::to_string);
+    query.limit = Some(query.limit.unwrap_or(100).min(MAX_HISTORY_LIMIT));
+    let history = state.store.read().unwrap().history(&query)?;
+    Ok(Json(history))
+}
+
+#[utoipa::path(get, path = "/v1/reservations", responses(
+    (status = 200, description = "Permanent reservations", body = [Reservation]),
+))]
+async fn list_reservations(
+    State(state): State<AppState>,
+) -> Json<Vec<Reservation>> {
+    let store = state.store.read().unwrap();
+    let mut reservations: Vec<Reservation> = store.reservations().values().cloned().collect();
+    reservations.sort_by_key(|r| r.port);
+    Json(reservations)
+}
+
+#[utoipa::path(post, path = "/v1/reservations", request_body = ReserveRequest, responses(
+    (status = 200, description = "Port reserved", body = Reservation),
+    (status = 400, description = "Invalid service name or excluded port", body = ApiError),
+    (status = 403, description = "Caller is not an admin", body = ApiError),
+    (status = 409, description = "Port held by another service", body = ApiError),
+))]
+async fn reserve_port(
+    State(state): State<AppState>,
+    identity: Identity,
+    ApiJson(payload): ApiJson<ReserveRequest>,
+) -> ApiResult<Json<Reservation>> {
+    identity.require(TokenScope::Admin)?;
+    validate_service_name(&payload.service_name)?;
+    if state.excluded.read().unwrap().iter().any(|r| r.contains(&payload.port)) {
+        return Err(ApiError::new(ErrorCode::PortExcluded, format!("Port {} is excluded from allocation", payload.port))
+            .with_details(serde_json::json!({ "port": payload.port }))
+            .into());
+    }
+
+    let mut store = state.store.write().unwrap();
+
+    // The port may already be leased, but only by the service it is reserved for
+    let taken_by_other = store.leases().get(&payload.port).is_some_and(|l| l.service_name != payload.service_name)
+        || store.reservations().get(&payload.port).is_some_and(|r| r.service_name != payload.service_name);
+    if taken_by_other {
+        return Err(ApiError::new(ErrorCode::Conflict, format!("Port {} is held by another service", payload.port))
+            .with_details(serde_json::json!({ "port": payload.port }))
+            .into());
+    }
 
-    if let Some(lease) = leases.get_mut(&payload.port) {
-        let now = Utc::now();
-        lease.last_heartbeat = now;
+    let reservation = Reservation {
+        port: payload.port,
+        service_name: payload.service_name,
+        reserved_at: Utc::now(),
+    };
 
-        // Also update database
-        let db = state.db.lock().unwrap();
-        let _ = db::update_heartbeat(&db, payload.port, now);
+    store.insert_reservation(reservation.clone())?;
+    Ok(Json(reservation))
+}
 
+#[utoipa::path(delete, path = "/v1/reservations/{port}", params(("port" = u16, Path, description = "Reserved port")), responses(
+    (status = 200, description = "Reservation removed"),
+    (status = 403, description = "Caller is not an admin", body = ApiError),
+    (status = 404, description = "Port not reserved", body = ApiError),
+))]
+async fn unreserve_port(
+    State(state): State<AppState>,
+    identity: Identity,
+    Path(port): Path<u16>,
+) -> ApiResult<StatusCode> {
+    identity.require(TokenScope::Admin)?;
+    let mut store = state.store.write().unwrap();
+
+    if store.remove_reservation(port)?.is_some() {
         Ok(StatusCode::OK)
     } else {
-        Err(StatusCode::NOT_FOUND)
+        Err(ApiError::new(ErrorCode::NotFound, format!("Port {} is not reserved", port)).into())
     }
 }
 
-async fn list_leases(
+#[utoipa::path(get, path = "/v1/pools", responses(
+    (status = 200, description = "Port pools", body = [Pool]),
+))]
+async fn list_pools(
+    State(state): State<AppState>,
+) -> Json<Vec<Pool>> {
+    let pools = state.pools.read().unwrap();
+    let mut pools: Vec<Pool> = pools.values().cloned().collect();
+    pools.sort_by_key(|p| p.min_port);
+    Json(pools)
+}
+
+#[utoipa::path(put, path = "/v1/pools/{name}", params(("name" = String, Path, description = "Pool name")), request_body = PoolSpec, responses(
+    (status = 200, description = "Pool created or updated", body = Pool),
+    (status = 400, description = "Invalid range or default pool", body = ApiError),
+    (status = 403, description = "Caller is not an admin", body = ApiError),
+    (status = 409, description = "New range would orphan active leases", body = ApiError),
+))]
+async fn put_pool(
+    State(state): State<AppState>,
+    identity: Identity,
+    Path(name): Path<String>,
+    ApiJson(spec): ApiJson<PoolSpec>,
+) -> ApiResult<Json<Pool>> {
+    identity.require(TokenScope::Admin)?;
+    // The default pool is owned by the daemon configuration
+    if name == DEFAULT_POOL {
+        return Err(ApiError::invalid_request("the default pool is defined by the daemon configuration").into());
+    }
+    // Port 0 asks the OS for any free port, so it can never be leased
+    if spec.min_port == 0 {
+        return Err(ApiError::invalid_request("min_port must be at least 1").into());
+    }
+    if spec.min_port > spec.max_port {
+        return Err(ApiError::invalid_request(format!("min_port ({}) is greater than max_port ({})", spec.min_port, spec.max_port)).into());
+    }
+
+    let default_ttl = state.config.read().unwrap().default_ttl;
+    let mut store = state.store.write().unwrap();
+    let mut pools = state.pools.write().unwrap();
+
+    // Refuse to shrink a pool below ports that are still leased from it
+    let orphaned: Vec<u16> = store
+        .leases()
+        .values()
+        .filter(|l| l.pool == name && !(spec.min_port..=spec.max_port).contains(&l.port))
+        .map(|l| l.port)
+        .collect();
+    if !orphaned.is_empty() {
+        return Err(ApiError::new(ErrorCode::Conflict, format!("New range would orphan active leases in pool '{}'", name))
+            .with_details(serde_json::json!({ "pool": name, "ports": orphaned }))
+            .into());
+    }
+
+    let pool = Pool {
+        name: name.clone(),
+        min_port: spec.min_port,
+        max_port: spec.max_port,
+        default_ttl: spec.default_ttl.unwrap_or(default_ttl),
+        strategy: spec.strategy,
+    };
+
+    store.save_pool(&pool)?;
+
+    pools.insert(name, pool.clone());
+    Ok(Json(pool))
+}
+
+#[utoipa::path(delete, path = "/v1/pools/{name}", params(("name" = String, Path, description = "Pool name")), responses(
+    (status = 200, description = "Pool deleted"),
+    (status = 403, description = "Caller is not an admin", body = ApiError),
+    (status = 404, description = "Unknown pool", body = ApiError),
+    (status = 409, description = "Pool still has active leases", body = ApiError),
+))]
+async fn delete_pool(
     State(state): State<AppState>,
-) -> Json<Vec<Lease>> {
-    let leases = state.leases.read().unwrap();
-    Json(leases.values().cloned().collect())
+    identity: Identity,
+    Path(name): Path<String>,
+) -> ApiResult<StatusCode> {
+    identity.require(TokenScope::Admin)?;
+    if name == DEFAULT_POOL {
+        return Err(ApiError::invalid_request("the default pool cannot be deleted").into());
+    }
+
+    let mut store = state.store.write().unwrap();
+    let mut pools = state.pools.write().unwrap();
+
+    if !pools.contains_key(&name) {
+        return Err(ApiError::pool_not_found(&name).into());
+    }
+    let active: Vec<u16> = store.leases().values().filter(|l| l.pool == name).map(|l| l.port).collect();
+    if !active.is_empty() {
+        return Err(ApiError::new(ErrorCode::Conflict, format!("Pool '{}' still has active leases", name))
+            .with_details(serde_json::json!({ "pool": name, "ports": active }))
+            .into());
+    }
+
+    store.delete_pool(&name)?;
+    pools.remove(&name);
+    Ok(StatusCode::OK)
 }
 
+#[utoipa::path(get, path = "/v1/lookup", params(
+    ("service" = String, Query, description = "Service name"),
+    ("namespace" = Option<String>, Query, description = "Only leases from this namespace; other namespaces need admin rights"),
+), responses(
+    (status = 200, description = "Ports leased by the service and visible to the caller", body = LookupResponse),
+    (status = 400, description = "Missing service parameter", body = ApiError),
+    (status = 403, description = "Namespace not visible to the caller", body = ApiError),
+))]
 async fn lookup_service(
     State(state): State<AppState>,
+    identity: Identity,
     Query(params): Query<HashMap<String, String>>,
-) -> Result<Json<LookupResponse>, StatusCode> {
-    let service_name = params.get("service").ok_or(StatusCode::BAD_REQUEST)?;
-
-    let leases = state.leases.read().unwrap();
-    let matching: Vec<&Lease> = leases
+) -> ApiResult<Json<LookupResponse>> {
+    let service_name = params.get("service")
+        .ok_or_else(|| ApiError::invalid_request("missing query parameter 'service'"))?;
+    let namespace = identity.visible_namespace(params.get("namespace").map(String::as_str))?;
+
+    let store = state.store.read().unwrap();
+    let matching: Vec<&Lease> = store
+        .leases()
         .values()
         .filter(|l| l.service_name == *service_name)
+        .filter(|l| namespace.is_none_or(|n| l.namespace == n))
         .collect();
 
     if matching.is_empty() {
@@ -287,3 +978,35 @@ async fn lookup_service(
         }))
     }
 }
+
+/// Rescan for unmanaged listeners and store the result as the latest snapshot.
+fn refresh_conflicts(state: &AppState) -> Vec<Conflict> {
+    let found = {
+        let store = state.store.read().unwrap();
+        let pools = state.pools.read().unwrap();
+        let excluded = state.excluded.read().unwrap();
+        conflicts::scan(pools.values(), &excluded, store.leases())
+    };
+    let mut current = state.conflicts.write().unwrap();
+    for conflict in found.iter().filter(|c| !current.iter().any(|p| p.port == c.port)) {
+        state.events.publish_conflict(conflict);
+    }
+    *current = found.clone();
+    found
+}
+
+#[utoipa::path(get, path = "/v1/conflicts", params(ConflictsParams), responses(
+    (status = 200, description = "Unmanaged listeners in pool ranges", body = [Conflict]),
+    (status = 400, description = "Invalid query", body = ApiError),
+))]
+async fn list_conflicts(
+    State(state): State<AppState>,
+    query: Result<Query<ConflictsParams>, QueryRejection>,
+) -> ApiResult<Json<Vec<Conflict>>> {
+    let Query(params) = query.map_err(|e| ApiError::invalid_request(e.body_text()))?;
+    if params.refresh {
+        return Ok(Json(refresh_conflicts(&state)));
+    }
+    // Scanning walks /proc, so serve the background scanner's snapshot by default
+    Ok(Json(state.conflicts.read().unwrap().clone()))
+}
@@ -0,0 +1,349 @@
+use chrono::Utc;
+use common::{DEFAULT_NAMESPACE, DEFAULT_POOL};
+use rusqlite::{Connection, Transaction};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Ordered schema migrations. Migration `i` brings the database from version
+/// `i` to `i + 1`, as recorded in `PRAGMA user_version`.
+/// Never edit or reorder an entry once released; append a new one instead.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("create leases table", create_leases),
+    ("add owner, group, role and pool to leases", add_lease_columns),
+    ("create pools, reservations, sticky_ports and lease_events", create_support_tables),
+    ("add namespace to leases", add_lease_namespace),
+    ("create api_tokens", create_api_tokens),
+    ("add namespace to lease_events", add_history_namespace),
+    ("key sticky_ports by namespace", add_sticky_port_namespace),
+];
+
+/// Schema version this build writes.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Bring the database up to `SCHEMA_VERSION`, one transaction per migration.
+///
+/// A file with pending migrations is copied next to itself first. A database
+/// written by a newer PortManager is refused rather than guessed at.
+pub fn run(conn: &mut Connection, path: Option<&Path>) -> Result<(), String> {
+    let version: u32 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| format!("failed to read schema version: {}", e))?;
+
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "database schema version {} is newer than this daemon supports ({}); upgrade PortManager",
+            version, SCHEMA_VERSION
+        ));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    if let Some(path) = path.filter(|_| has_tables(conn)) {
+        let backup = backup(path, version)?;
+        println!("Backed up database to {} before migrating", backup.display());
+    }
+
+    for (index, (description, migrate)) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let target = index as u32 + 1;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        migrate(&tx)
+            .and_then(|_| tx.pragma_update(None, "user_version", target))
+            .and_then(|_| tx.commit())
+            .map_err(|e| format!("migration {} ({}) failed: {}", target, description, e))?;
+        println!("Applied database migration {}: {}", target, description);
+    }
+    Ok(())
+}
+
+fn has_tables(conn: &Connection) -> bool {
+    conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'", [], |row| row.get::<_, i64>(0))
+        .map(|count| count > 0)
+        .unwrap_or(false)
+}
+
+/// Copy the database file to `<file>.v<version>-<timestamp>.bak`.
+fn backup(path: &Path, version: u32) -> Result<PathBuf, String> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".v{}-{}.bak", version, Utc::now().format("%Y%m%d%H%M%S")));
+    let backup = path.with_file_name(name);
+    fs::copy(path, &backup).map_err(|e| format!("failed to back up {}: {}", path.display(), e))?;
+    Ok(backup)
+}
+
+// Databases from before versioning report user_version 0 but may already
+// contain some of these tables and columns, so the first migrations tolerate them.
+
+fn create_leases(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS leases (
+            port INTEGER PRIMARY KEY,
+            service_name TEXT NOT NULL,
+            allocated_at TEXT NOT NULL,
+            last_heartbeat TEXT NOT NULL,
+            ttl_seconds INTEGER NOT NULL,
+            tags TEXT NOT NULL
+        );",
+    )
+}
+
+fn add_lease_columns(tx: &Transaction) -> rusqlite::Result<()> {
+    let existing: Vec<String> = tx
+        .prepare("PRAGMA table_info(leases)")?
+        .query_map([], |row| row.get(1))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let pool_decl = format!("TEXT NOT NULL DEFAULT '{}'", DEFAULT_POOL);
+    let columns = [
+        ("owner_pid", "INTEGER"),
+        ("owner_start_time", "INTEGER"),
+        ("group_id", "TEXT"),
+        ("role", "TEXT"),
+        ("pool", pool_decl.as_str()),
+    ];
+    for (column, decl) in columns {
+        if !existing.iter().any(|c| c == column) {
+            tx.execute_batch(&format!("ALTER TABLE leases ADD COLUMN {} {}", column, decl))?;
+        }
+    }
+    Ok(())
+}
+
+fn create_support_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS pools (
+            name TEXT PRIMARY KEY,
+            min_port INTEGER NOT NULL,
+            max_port INTEGER NOT NULL,
+            default_ttl INTEGER NOT NULL,
+            strategy TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS reservations (
+            port INTEGER PRIMARY KEY,
+            service_name TEXT NOT NULL,
+            reserved_at TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS sticky_ports (
+            service_name TEXT PRIMARY KEY,
+            port INTEGER NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+
+        -- Append-only audit log. Timestamps are fixed-width RFC 3339 so they sort as text.
+        CREATE TABLE IF NOT EXISTS lease_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            port INTEGER NOT NULL,
+            service_name TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            reason TEXT,
+            at TEXT NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS lease_events_at ON lease_events (at);",
+    )
+}
+
+fn add_lease_namespace(tx: &Transaction) -> rusqlite::Result<()> {
+    // Leases from before namespaces belong to the user running the daemon
+    tx.execute_batch(&format!("ALTER TABLE leases ADD COLUMN namespace TEXT NOT NULL DEFAULT '{}'", DEFAULT_NAMESPACE))
+}
+
+fn add_history_namespace(tx: &Transaction) -> rusqlite::Result<()> {
+    // Entries cannot be traced back to their lease, so older ones go to the default namespace
+    tx.execute_batch(&format!(
+        "ALTER TABLE lease_events ADD COLUMN namespace TEXT NOT NULL DEFAULT '{}';
+         CREATE INDEX lease_events_namespace ON lease_events (namespace, id);",
+        DEFAULT_NAMESPACE
+    ))
+}
+
+fn add_sticky_port_namespace(tx: &Transaction) -> rusqlite::Result<()> {
+    // A sticky port goes to the namespace of the lease still holding it, if any
+    tx.execute_batch(&format!(
+        "ALTER TABLE sticky_ports RENAME TO sticky_ports_old;
+         CREATE TABLE sticky_ports (
+            namespace TEXT NOT NULL,
+            service_name TEXT NOT NULL,
+            port INTEGER NOT NULL,
+            updated_at TEXT NOT NULL,
+            PRIMARY KEY (namespace, service_name)
+         );
+         INSERT INTO sticky_ports (namespace, service_name, port, updated_at)
+            SELECT COALESCE(
+                (SELECT namespace FROM leases WHERE leases.port = old.port AND leases.service_name = old.service_name),
+                '{}'
+            ), service_name, port, updated_at
+            FROM sticky_ports_old AS old;
+         DROP TABLE sticky_ports_old;",
+        DEFAULT_NAMESPACE
+    ))
+}
+
+fn create_api_tokens(tx: &Transaction) -> rusqlite::Result<()> {
+    // Only a hash of each secret is kept
+    tx.execute_batch(
+        "CREATE TABLE api_tokens (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            secret_hash TEXT NOT NULL UNIQUE,
+            namespace TEXT NOT NULL,
+            scope TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database file in a directory of its own, removed when dropped.
+    struct TempDb {
+        dir: PathBuf,
+        path: PathBuf,
+    }
+
+    impl TempDb {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("portmanager-migrations-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("leases.db");
+            TempDb { dir, path }
+        }
+
+        fn open(&self) -> Connection {
+            Connection::open(&self.path).unwrap()
+        }
+
+        fn backups(&self) -> Vec<PathBuf> {
+            fs::read_dir(&self.dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "bak"))
+                .collect()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn user_version(conn: &Connection) -> u32 {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn upgrades_pre_versioning_database_and_backs_it_up() {
+        let db = TempDb::new();
+        // The schema and data of a release from before migrations
+        db.open()
+            .execute_batch(
+                "CREATE TABLE leases (
+                    port INTEGER PRIMARY KEY,
+                    service_name TEXT NOT NULL,
+                    allocated_at TEXT NOT NULL,
+                    last_heartbeat TEXT NOT NULL,
+                    ttl_seconds INTEGER NOT NULL,
+                    tags TEXT NOT NULL
+                );
+                INSERT INTO leases VALUES (8080, 'old-api', '2024-01-01T00:00:00+00:00', '2024-01-01T00:00:00+00:00', 300, '[\"legacy\"]');",
+            )
+            .unwrap();
+
+        let mut conn = db.open();
+        run(&mut conn, Some(&db.path)).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+
+        let leases = crate::db::load_leases(&conn).unwrap();
+        let lease = &leases[&8080];
+        assert_eq!(lease.service_name, "old-api");
+        assert_eq!(lease.tags, vec!["legacy".to_string()]);
+        assert_eq!(lease.pool, DEFAULT_POOL);
+        assert_eq!(lease.namespace, DEFAULT_NAMESPACE);
+
+        // The backup is the database as it was
+        let backups = db.backups();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].file_name().unwrap().to_string_lossy().starts_with("leases.db.v0-"));
+        let backup = Connection::open(&backups[0]).unwrap();
+        assert_eq!(user_version(&backup), 0);
+        let columns: i64 = backup.query_row("SELECT COUNT(*) FROM pragma_table_info('leases')", [], |row| row.get(0)).unwrap();
+        assert_eq!(columns, 6);
+    }
+
+    #[test]
+    fn upgrades_from_every_version() {
+        for version in 0..SCHEMA_VERSION {
+            let mut conn = Connection::open_in_memory().unwrap();
+            for (_, migrate) in &MIGRATIONS[..version as usize] {
+                let tx = conn.transaction().unwrap();
+                migrate(&tx).unwrap();
+                tx.commit().unwrap();
+            }
+            conn.pragma_update(None, "user_version", version).unwrap();
+
+            run(&mut conn, None).unwrap_or_else(|e| panic!("from version {}: {}", version, e));
+            assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        }
+    }
+
+    #[test]
+    fn sticky_ports_move_to_the_namespace_holding_them() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let version = MIGRATIONS.iter().position(|(d, _)| *d == "key sticky_ports by namespace").unwrap();
+        for (_, migrate) in &MIGRATIONS[..version] {
+            let tx = conn.transaction().unwrap();
+            migrate(&tx).unwrap();
+            tx.commit().unwrap();
+        }
+        conn.pragma_update(None, "user_version", version as u32).unwrap();
+        conn.execute_batch(
+            "INSERT INTO leases (port, service_name, allocated_at, last_heartbeat, ttl_seconds, tags, namespace)
+                VALUES (8001, 'api', '2024-01-01T00:00:00+00:00', '2024-01-01T00:00:00+00:00', 300, '[]', 'alice');
+             INSERT INTO sticky_ports VALUES ('api', 8001, '2024-01-01T00:00:00+00:00');
+             INSERT INTO sticky_ports VALUES ('db', 8002, '2024-01-01T00:00:00+00:00');",
+        )
+        .unwrap();
+
+        run(&mut conn, None).unwrap();
+        let sticky = crate::db::load_sticky_ports(&conn).unwrap();
+        assert_eq!(sticky.len(), 2);
+        assert_eq!(sticky[&("alice".to_string(), "api".to_string())], 8001);
+        assert_eq!(sticky[&(DEFAULT_NAMESPACE.to_string(), "db".to_string())], 8002);
+    }
+
+    #[test]
+    fn rerunning_is_a_no_op() {
+        let db = TempDb::new();
+        let mut conn = db.open();
+        run(&mut conn, Some(&db.path)).unwrap();
+        // A new database has nothing worth backing up
+        assert!(db.backups().is_empty());
+
+        run(&mut conn, Some(&db.path)).unwrap();
+        drop(conn);
+        let mut conn = db.open();
+        run(&mut conn, Some(&db.path)).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert!(db.backups().is_empty());
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let db = TempDb::new();
+        let mut conn = db.open();
+        conn.execute_batch("CREATE TABLE leases (port INTEGER PRIMARY KEY, future TEXT)").unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+
+        let error = run(&mut conn, Some(&db.path)).unwrap_err();
+        assert!(error.contains("newer than this daemon supports"), "{}", error);
+        assert_eq!(user_version(&conn), SCHEMA_VERSION + 1);
+        assert!(db.backups().is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::config::Config;
+use crate::error::AppError;
+use crate::socket::Caller;
+use axum::{extract::FromRequestParts, http::request::Parts};
+use common::{ApiError, ErrorCode, Lease, TokenScope, ANONYMOUS_NAMESPACE, DEFAULT_NAMESPACE};
+
+/// The namespace a request acts in, and what it may do there.
+///
+/// Resolved once per request by `auth::authenticate`: from the API token if
+/// one is sent, otherwise from the connection. Callers on the Unix socket get
+/// a namespace named after their user, except the daemon's own user, who is
+/// an admin in `DEFAULT_NAMESPACE`. Anonymous TCP callers may allocate in
+/// `ANONYMOUS_NAMESPACE`, apart from every user; anything more needs a token.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub namespace: String,
+    pub scope: TokenScope,
+    pub caller: Caller,
+}
+
+impl Identity {
+    /// Identity of a caller that sent no token.
+    pub fn from_peer(caller: Caller, config: &Config) -> Self {
+        match caller {
+            Caller::Local { uid, .. } if uid != daemon_uid() => {
+                let namespace = user_name(uid).unwrap_or_else(|| format!("uid-{}", uid));
+                let admin = uid == 0 || config.admins.contains(&namespace);
+                let scope = if admin { TokenScope::Admin } else { TokenScope::Allocate };
+                Identity { namespace, scope, caller }
+            }
+            Caller::Local { .. } => Identity { namespace: DEFAULT_NAMESPACE.to_string(), scope: TokenScope::Admin, caller },
+            // Anyone who can reach the port could claim to be anyone, so they share
+            // a namespace that no socket user's leases are in
+            Caller::Tcp => Identity { namespace: ANONYMOUS_NAMESPACE.to_string(), scope: TokenScope::Allocate, caller },
+        }
+    }
+
+    /// May see and act on leases of every namespace.
+    pub fn admin(&self) -> bool {
+        self.scope == TokenScope::Admin
+    }
+
+    pub fn can_access(&self, lease: &Lease) -> bool {
+        self.admin() || lease.namespace == self.namespace
+    }
+
+    /// Namespace a listing is limited to. Admins see every namespace unless they
+    /// ask for one; everyone else only their own.
+    pub fn visible_namespace<'a>(&'a self, requested: Option<&'a str>) -> Result<Option<&'a str>, ApiError> {
+        match requested {
+            Some(namespace) if !self.admin() && namespace != self.namespace => {
+                Err(ApiError::forbidden(format!("Namespace '{}' is not yours", namespace)))
+            }
+            Some(namespace) => Ok(Some(namespace)),
+            None if self.admin() => Ok(None),
+            None => Ok(Some(&self.namespace)),
+        }
+    }
+
+    /// Fail unless the caller's scope includes `scope`.
+    pub fn require(&self, scope: TokenScope) -> Result<(), ApiError> {
+        if self.scope >= scope {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(format!("This requires the '{}' scope", scope.as_str()))
+                .with_details(serde_json::json!({ "required": scope, "scope": self.scope })))
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Identity {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Identity>()
+            .cloned()
+            .ok_or_else(|| ApiError::new(ErrorCode::Internal, "Request was not authenticated").into())
+    }
+}
+
+/// Whether `value` works as a namespace or token name: 1-128 characters, no control characters.
+pub fn valid_name(value: &str) -> bool {
+    !value.is_empty() && value.chars().count() <= 128 && !value.chars().any(char::is_control)
+}
+
+#[cfg(unix)]
+fn daemon_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+#[cfg(not(unix))]
+fn daemon_uid() -> u32 {
+    // There is no socket listener, so no caller has a uid to compare
+    0
+}
+
+/// Login name of `uid` from the password database.
+#[cfg(unix)]
+fn user_name(uid: u32) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let rc = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if rc != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn user_name(_uid: u32) -> Option<String> {
+    None
+}
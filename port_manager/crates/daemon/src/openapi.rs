@@ -0,0 +1,34 @@
+use axum::Json;
+use utoipa::OpenApi;
+
+/// OpenAPI description of the `/v1` API, generated from the handlers and the `common` types.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "PortManager API", description = "Port leases for local development"),
+    paths(
+        crate::allocate_port,
+        crate::release_port,
+        crate::heartbeat,
+        crate::list_leases,
+        crate::lookup_service,
+        crate::list_conflicts,
+        crate::list_pools,
+        crate::put_pool,
+        crate::delete_pool,
+        crate::list_reservations,
+        crate::reserve_port,
+        crate::unreserve_port,
+        crate::events::events,
+        crate::list_history,
+        crate::export::export_state,
+        crate::export::import_state,
+        crate::auth::list_tokens,
+        crate::auth::create_token,
+        crate::auth::revoke_token,
+    )
+)]
+pub struct ApiDoc;
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
@@ -0,0 +1,147 @@
+use crate::config::Config;
+use crate::error::AppError;
+use crate::socket::Caller;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use common::{ApiError, ErrorCode};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Browser origins that may call the API: the dashboard itself and `cors_origins`,
+/// and the `Host` names it may be called by.
+///
+/// Fixed at startup, like the listeners the dashboard is served on.
+#[derive(Clone)]
+pub struct Origins {
+    any: bool,
+    allowed: Arc<Vec<String>>,
+    listen: Arc<Vec<SocketAddr>>,
+}
+
+impl Origins {
+    pub fn new(config: &Config) -> Self {
+        // The dashboard is reached by a listen address, or as localhost on a loopback one
+        let mut allowed = Vec::new();
+        for addr in &config.listen {
+            allowed.push(format!("http://{}", addr));
+            if addr.ip().is_loopback() {
+                allowed.push(format!("http://localhost:{}", addr.port()));
+                allowed.push(format!("http://127.0.0.1:{}", addr.port()));
+                allowed.push(format!("http://[::1]:{}", addr.port()));
+            }
+        }
+        allowed.sort();
+        allowed.dedup();
+        allowed.extend(config.cors_origins.iter().filter(|o| *o != "*").cloned());
+        Origins {
+            any: config.cors_origins.iter().any(|o| o == "*"),
+            allowed: Arc::new(allowed),
+            listen: Arc::new(config.listen.clone()),
+        }
+    }
+
+    /// Whether a request with this `Host` header was meant for the daemon.
+    ///
+    /// A DNS-rebinding page reaches a listen address under the attacker's own
+    /// host name, which makes it same-origin and so untouched by CORS. Only IP
+    /// addresses we listen on, `localhost` on loopback ones, and the hosts of
+    /// `cors_origins` are accepted.
+    pub fn allows_host(&self, host: &str) -> bool {
+        if self.any || self.allowed.iter().any(|o| origin_host(o).eq_ignore_ascii_case(host)) {
+            return true;
+        }
+        let Some((name, port)) = split_host(host) else {
+            return false;
+        };
+        self.listen.iter().filter(|addr| addr.port() == port).any(|addr| {
+            let listen_ip = addr.ip();
+            match name.parse::<IpAddr>() {
+                Ok(ip) => ip == listen_ip || listen_ip.is_unspecified() || (ip.is_loopback() && listen_ip.is_loopback()),
+                Err(_) => name.eq_ignore_ascii_case("localhost") && (listen_ip.is_loopback() || listen_ip.is_unspecified()),
+            }
+        })
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        self.any || self.allowed.iter().any(|o| o.eq_ignore_ascii_case(origin))
+    }
+
+    /// Browsers may only call the API from the allowed origins, so that other
+    /// web pages cannot release or allocate ports.
+    pub fn cors_layer(&self) -> CorsLayer {
+        let layer = CorsLayer::new()
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION]);
+        if self.any {
+            return layer.allow_origin(AllowOrigin::any());
+        }
+        layer.allow_origin(AllowOrigin::list(self.allowed.iter().filter_map(|o| HeaderValue::from_str(o).ok())))
+    }
+}
+
+/// Reject TCP requests whose `Host` is not one of ours; see [`Origins::allows_host`].
+/// Socket callers are identified by their uid and cannot be rebound to.
+pub async fn check_host(State(origins): State<Origins>, caller: Caller, request: Request, next: Next) -> Response {
+    let host = request.headers().get(header::HOST).and_then(|h| h.to_str().ok());
+    if caller == Caller::Tcp && !host.is_some_and(|h| origins.allows_host(h)) {
+        let message = format!("Host '{}' is not served by this daemon", host.unwrap_or_default());
+        return AppError(ApiError::new(ErrorCode::Forbidden, message)).into_response();
+    }
+    next.run(request).await
+}
+
+/// `host:port` of an origin such as `http://devbox:3030`.
+fn origin_host(origin: &str) -> &str {
+    origin.split_once("://").map_or(origin, |(_, host)| host).trim_end_matches('/')
+}
+
+/// Host name or address and port of a `Host` header; the port defaults to 80.
+fn split_host(host: &str) -> Option<(&str, u16)> {
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => {
+            let (name, rest) = rest.split_once(']')?;
+            (name, rest.strip_prefix(':'))
+        }
+        None => match host.rsplit_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (host, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => 80,
+    };
+    Some((name, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_listen_addresses_and_loopback_names_are_hosts() {
+        let origins = Origins::new(&Config::default());
+        for host in ["localhost:3030", "LOCALHOST:3030", "127.0.0.1:3030", "127.0.0.2:3030", "[::1]:3030"] {
+            assert!(origins.allows_host(host), "{}", host);
+        }
+        for host in ["evil.example:3030", "localhost:3031", "localhost", "192.168.1.10:3030", "[::1", ""] {
+            assert!(!origins.allows_host(host), "{}", host);
+        }
+
+        let config = Config {
+            listen: vec!["0.0.0.0:3030".parse().unwrap()],
+            cors_origins: vec!["http://devbox:3030".to_string()],
+            ..Config::default()
+        };
+        let origins = Origins::new(&config);
+        for host in ["192.168.1.10:3030", "localhost:3030", "devbox:3030"] {
+            assert!(origins.allows_host(host), "{}", host);
+        }
+        assert!(!origins.allows_host("evil.example:3030"));
+    }
+}
@@ -0,0 +1,152 @@
+use common::SkipReason;
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+
+/// TCP state code for LISTEN in /proc/net/tcp{,6}.
+const TCP_LISTEN: &str = "0A";
+
+/// A listening socket read from /proc/net/tcp{,6}.
+#[derive(Debug, Clone)]
+pub struct Listener {
+    pub port: u16,
+    /// Socket inode, used to find the owning process via /proc/<pid>/fd.
+    pub inode: u64,
+}
+
+/// Read all listening TCP sockets from /proc/net/tcp and /proc/net/tcp6.
+/// Returns an empty list on platforms without procfs.
+pub fn read_listeners() -> Vec<Listener> {
+    ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .flat_map(|contents| parse_proc_net_tcp(&contents))
+        .collect()
+}
+
+/// Parse the contents of a /proc/net/tcp{,6} file, keeping only LISTEN sockets.
+fn parse_proc_net_tcp(contents: &str) -> Vec<Listener> {
+    contents
+        .lines()
+        .skip(1) // header
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || fields[3] != TCP_LISTEN {
+                return None;
+            }
+            // local_address is "ADDR:PORT" with the port in hex
+            let (_, port_hex) = fields[1].rsplit_once(':')?;
+            let port = u16::from_str_radix(port_hex, 16).ok()?;
+            let inode = fields[9].parse().ok()?;
+            Some(Listener { port, inode })
+        })
+        .collect()
+}
+
+/// Snapshot of the OS view of the port space, taken once per allocation so
+/// procfs is not re-read for every candidate.
+pub struct PortProbe {
+    listening: HashSet<u16>,
+    bind: bool,
+}
+
+impl PortProbe {
+    pub fn new() -> Self {
+        Self {
+            listening: read_listeners().into_iter().map(|l| l.port).collect(),
+            bind: true,
+        }
+    }
+
+    /// A probe that sees exactly `listening` in use, without asking the OS.
+    #[cfg(test)]
+    pub fn fake(listening: &[u16]) -> Self {
+        Self { listening: listening.iter().copied().collect(), bind: false }
+    }
+
+    /// Check whether the OS considers `port` free.
+    /// Returns the reason together with a human readable detail if it is not.
+    pub fn check(&self, port: u16) -> Result<(), (SkipReason, String)> {
+        if self.listening.contains(&port) {
+            return Err((SkipReason::InUse, "listening socket found in /proc/net/tcp".to_string()));
+        }
+
+        if !self.bind {
+            return Ok(());
+        }
+        // Binding the wildcard and loopback address of both families catches
+        // listeners on any of them, including on platforms without procfs.
+        let addresses: [IpAddr; 4] =
+            [Ipv4Addr::UNSPECIFIED.into(), Ipv4Addr::LOCALHOST.into(), Ipv6Addr::UNSPECIFIED.into(), Ipv6Addr::LOCALHOST.into()];
+        for ip in addresses {
+            let addr = SocketAddr::new(ip, port);
+            match TcpListener::bind(addr) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                    return Err((SkipReason::InUse, format!("address {} already in use", addr)));
+                }
+                // Hosts without IPv6 cannot have IPv6 listeners either
+                Err(_) if ip.is_ipv6() => {}
+                Err(e) => return Err((SkipReason::BindFailed, e.to_string())),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROC_NET_TCP: &str = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 41234 1 0000000000000000 100 0 0 10 0
+   1: 00000000:0BB8 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 51000 1 0000000000000000 100 0 0 10 0
+   2: 0100007F:1F90 0100007F:D2F4 01 00000000:00000000 00:00000000 00000000  1000        0 41240 1 0000000000000000 20 4 30 10 -1
+   3: 0100007F:FFFF 00000000:0000 0A
+";
+
+    const PROC_NET_TCP6: &str = "\
+  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000000000000000000000000000:0050 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 61000 1 0000000000000000 100 0 0 10 0
+   1: 00000000000000000000000001000000:C350 00000000000000000000000001000000:8AE2 06 00000000:00000000 03:00000F9F 00000000     0        0 0 3 0000000000000000
+";
+
+    fn ports(listeners: &[Listener]) -> Vec<(u16, u64)> {
+        listeners.iter().map(|l| (l.port, l.inode)).collect()
+    }
+
+    #[test]
+    fn only_listening_sockets_are_parsed() {
+        // 0x1F90 is 8080 and 0x0BB8 is 3000; the established connection and the truncated line are left out
+        assert_eq!(ports(&parse_proc_net_tcp(PROC_NET_TCP)), vec![(8080, 41234), (3000, 51000)]);
+        assert_eq!(ports(&parse_proc_net_tcp(PROC_NET_TCP6)), vec![(80, 61000)]);
+        assert!(parse_proc_net_tcp("").is_empty());
+        assert!(parse_proc_net_tcp("header only").is_empty());
+    }
+
+    #[test]
+    fn listeners_from_procfs_count_as_in_use() {
+        let probe = PortProbe::fake(&[8080]);
+        assert_eq!(probe.check(8080).unwrap_err().0, SkipReason::InUse);
+        assert!(probe.check(8081).is_ok());
+    }
+
+    /// A port held only by an IPv6 listener is taken, even though its IPv4 addresses are free.
+    #[test]
+    fn ipv6_only_listener_counts_as_in_use() {
+        let Ok(listener) = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)) else {
+            return; // No IPv6 on this host
+        };
+        let port = listener.local_addr().unwrap().port();
+        let probe = PortProbe { listening: HashSet::new(), bind: true };
+        assert_eq!(probe.check(port).unwrap_err().0, SkipReason::InUse);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(probe.check(port).unwrap_err().0, SkipReason::InUse);
+        drop(listener);
+        assert!(probe.check(port).is_ok());
+    }
+}
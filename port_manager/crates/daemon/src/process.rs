@@ -0,0 +1,93 @@
+/// Start time of a process, used to tell a live owner apart from an unrelated
+/// process that was later given the same PID.
+///
+/// On Linux this is field 22 of /proc/<pid>/stat (clock ticks since boot).
+/// Returns `None` if the process does not exist or the platform has no procfs.
+pub fn start_time(pid: u32) -> Option<u64> {
+    read_stat(pid).map(|(_, start)| start)
+}
+
+/// State (field 3) and start time (field 22) from /proc/<pid>/stat.
+fn read_stat(pid: u32) -> Option<(char, u64)> {
+    parse_stat(&std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+fn parse_stat(stat: &str) -> Option<(char, u64)> {
+    // The command name (field 2) may contain spaces and parentheses, so split after the last ')'
+    let (_, rest) = stat.rsplit_once(')')?;
+    // `rest` starts at field 3 (state), so starttime (field 22) is at index 19
+    let mut fields = rest.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let start = fields.nth(18)?.parse().ok()?;
+    Some((state, start))
+}
+
+/// Check whether the process that owns a lease is still running.
+///
+/// If `expected_start` is given and the platform can report start times, a
+/// process with the same PID but a different start time counts as gone. So
+/// does a zombie: it has exited and only waits for its parent to reap it.
+pub fn is_alive(pid: u32, expected_start: Option<u64>) -> bool {
+    if std::path::Path::new("/proc/self/stat").exists() {
+        return match (read_stat(pid), expected_start) {
+            (None, _) => false,
+            (Some(('Z' | 'X' | 'x', _)), _) => false,
+            (Some((_, actual)), Some(expected)) => actual == expected,
+            (Some(_), None) => true,
+        };
+    }
+    signal_check(pid)
+}
+
+#[cfg(unix)]
+fn signal_check(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 performs the permission and existence checks without sending anything.
+    // EPERM means the process exists but belongs to someone else.
+    let rc = unsafe { libc::kill(pid, 0) };
+    rc == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn signal_check(_pid: u32) -> bool {
+    // No cheap way to check; fall back to TTL-based expiry
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A /proc/<pid>/stat line for `comm` in `state`, started 4242 ticks after boot.
+    fn stat(comm: &str, state: char) -> String {
+        format!("1234 ({}) {} 1 1234 1234 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 4242 1000 50 18446744073709551615", comm, state)
+    }
+
+    #[test]
+    fn stat_fields_follow_the_last_parenthesis() {
+        assert_eq!(parse_stat(&stat("sleep", 'S')), Some(('S', 4242)));
+        assert_eq!(parse_stat(&stat("tmux: server", 'R')), Some(('R', 4242)));
+        // A command name can fake the fields that follow it
+        assert_eq!(parse_stat(&stat("evil) R 1 2 3", 'Z')), Some(('Z', 4242)));
+        assert_eq!(parse_stat(&stat(")(", 'X')), Some(('X', 4242)));
+        assert_eq!(parse_stat(&stat("", 'S')), Some(('S', 4242)));
+    }
+
+    #[test]
+    fn truncated_stat_is_rejected() {
+        assert_eq!(parse_stat("1234 (sleep S 1 1234"), None);
+        assert_eq!(parse_stat("1234 (sleep) S 1 1234"), None);
+        assert_eq!(parse_stat(""), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn own_process_is_alive() {
+        let pid = std::process::id();
+        let start = start_time(pid).unwrap();
+        assert!(is_alive(pid, Some(start)));
+        assert!(!is_alive(pid, Some(start + 1)));
+    }
+}
@@ -0,0 +1,79 @@
+use axum::{
+    extract::{connect_info::Connected, ConnectInfo, FromRequestParts},
+    http::request::Parts,
+    serve::IncomingStream,
+};
+use std::convert::Infallible;
+use std::fmt;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// Who sent a request, as far as the connection tells.
+///
+/// Requests over TCP are anonymous. Requests over the Unix socket carry the
+/// peer's credentials from `SO_PEERCRED` (`getpeereid` on macOS).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caller {
+    Tcp,
+    Local { uid: u32, pid: Option<i32> },
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Caller::Tcp => f.write_str("client"),
+            Caller::Local { uid, pid: Some(pid) } => write!(f, "uid {}, pid {}", uid, pid),
+            Caller::Local { uid, pid: None } => write!(f, "uid {}", uid),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, UnixListener>> for Caller {
+    fn connect_info(stream: IncomingStream<'_, UnixListener>) -> Self {
+        match stream.io().peer_cred() {
+            Ok(cred) => Caller::Local { uid: cred.uid(), pid: cred.pid() },
+            // Should not happen on a connected socket; treat the peer like a TCP one
+            Err(_) => Caller::Tcp,
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Only the Unix socket listener attaches connection info
+        Ok(parts.extensions.get::<ConnectInfo<Caller>>().map(|info| info.0).unwrap_or(Caller::Tcp))
+    }
+}
+
+/// Bind the API socket at `path` with permissions `mode`.
+///
+/// A socket file left behind by a daemon that is gone is replaced; one that
+/// still accepts connections means another daemon is running and is an error.
+#[cfg(unix)]
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener, String> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(format!("{} is in use by another daemon", path.display()));
+        }
+        std::fs::remove_file(path).map_err(|e| format!("failed to remove stale {}: {}", path.display(), e))?;
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.exists()) {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+    }
+
+    let listener = UnixListener::bind(path).map_err(|e| format!("failed to bind {}: {}", path.display(), e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .map_err(|e| format!("failed to set permissions on {}: {}", path.display(), e))?;
+    Ok(listener)
+}
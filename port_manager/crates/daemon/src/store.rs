@@ -0,0 +1,636 @@
+use crate::db;
+use chrono::{DateTime, Utc};
+use common::{ApiToken, HistoryEntry, HistoryKind, HistoryQuery, Lease, Pool, Reservation, StickyPort};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A failed storage operation. The in-memory state is unchanged when one is returned.
+#[derive(Debug)]
+pub struct StoreError(String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(error: rusqlite::Error) -> Self {
+        StoreError(error.to_string())
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// State restored by an import, written by `LeaseStore::import` all or nothing.
+#[derive(Debug, Default)]
+pub struct ImportBatch {
+    /// Drop every lease, reservation and sticky port first.
+    pub replace: bool,
+    pub leases: Vec<Lease>,
+    pub reservations: Vec<Reservation>,
+    pub sticky_ports: Vec<StickyPort>,
+}
+
+/// Everything the daemon persists: leases, sticky ports, reservations, named
+/// pools, API tokens and the lease history.
+///
+/// The store is the only copy of leases, sticky ports, reservations and tokens; reads
+/// are served from memory and writes reach the backend before memory changes.
+pub trait LeaseStore: Send + Sync {
+    /// Active leases, keyed by port.
+    fn leases(&self) -> &HashMap<u16, Lease>;
+    /// Add leases, all or none.
+    fn insert_leases(&mut self, leases: &[Lease]) -> StoreResult<()>;
+    /// Remove a lease and record why in the history, both or neither.
+    fn remove_lease(&mut self, port: u16, kind: HistoryKind, reason: &str, at: DateTime<Utc>) -> StoreResult<Option<Lease>>;
+    /// Record a heartbeat. Returns the updated lease, or `None` if the port is not leased.
+    fn touch_lease(&mut self, port: u16, at: DateTime<Utc>) -> StoreResult<Option<&Lease>>;
+
+    /// Port the service held last in the namespace.
+    fn sticky_port(&self, namespace: &str, service_name: &str) -> Option<u16>;
+    fn set_sticky_port(&mut self, namespace: &str, service_name: &str, port: u16, at: DateTime<Utc>) -> StoreResult<()>;
+    /// Sticky ports of all services, keyed by namespace and service name.
+    fn sticky_ports(&self) -> &HashMap<(String, String), u16>;
+
+    /// Permanent reservations, keyed by port.
+    fn reservations(&self) -> &HashMap<u16, Reservation>;
+    fn insert_reservation(&mut self, reservation: Reservation) -> StoreResult<()>;
+    fn remove_reservation(&mut self, port: u16) -> StoreResult<Option<Reservation>>;
+
+    /// API tokens, keyed by the SHA-256 hash of their secret.
+    fn tokens(&self) -> &HashMap<String, ApiToken>;
+    fn insert_token(&mut self, token: ApiToken, secret_hash: String) -> StoreResult<()>;
+    fn remove_token(&mut self, id: &str) -> StoreResult<Option<ApiToken>>;
+
+    /// Apply an import in one transaction. Leases dropped by a replacing import
+    /// are recorded as released and returned.
+    fn import(&mut self, batch: &ImportBatch, at: DateTime<Utc>) -> StoreResult<Vec<Lease>>;
+
+    /// Named pools created through the API. The default pool is not stored.
+    fn pools(&self) -> StoreResult<Vec<Pool>>;
+    fn save_pool(&mut self, pool: &Pool) -> StoreResult<()>;
+    fn delete_pool(&mut self, name: &str) -> StoreResult<()>;
+
+    fn record_history(&mut self, kind: HistoryKind, lease: &Lease, reason: Option<&str>, at: DateTime<Utc>) -> StoreResult<()>;
+    /// History entries matching the query, newest first.
+    fn history(&self, query: &HistoryQuery) -> StoreResult<Vec<HistoryEntry>>;
+    /// Delete history entries older than `before`. Returns the number removed.
+    fn prune_history(&mut self, before: DateTime<Utc>) -> StoreResult<usize>;
+}
+
+/// History reason of leases dropped by a replacing import.
+const REPLACED_BY_IMPORT: &str = "replaced by import";
+
+/// Store backed by the SQLite database, with leases, sticky ports and
+/// reservations cached in memory.
+pub struct SqliteStore {
+    // Only needed behind a lock for the read-only queries; writers have `&mut self`
+    conn: Mutex<Connection>,
+    leases: HashMap<u16, Lease>,
+    sticky_ports: HashMap<(String, String), u16>,
+    reservations: HashMap<u16, Reservation>,
+    tokens: HashMap<String, ApiToken>,
+}
+
+impl SqliteStore {
+    /// Open and migrate the database at `path` and load its contents.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let conn = db::init_db(path)?;
+        Self::load(conn).map_err(|e| format!("failed to load {}: {}", path.display(), e))
+    }
+
+    /// A migrated store in a private in-memory database.
+    #[cfg(test)]
+    fn open_in_memory() -> Self {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migrations::run(&mut conn, None).unwrap();
+        Self::load(conn).unwrap()
+    }
+
+    fn load(conn: Connection) -> rusqlite::Result<Self> {
+        Ok(Self {
+            leases: db::load_leases(&conn)?,
+            sticky_ports: db::load_sticky_ports(&conn)?,
+            reservations: db::load_reservations(&conn)?,
+            tokens: db::load_tokens(&conn)?,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&mut self) -> &mut Connection {
+        self.conn.get_mut().unwrap()
+    }
+}
+
+impl LeaseStore for SqliteStore {
+    fn leases(&self) -> &HashMap<u16, Lease> {
+        &self.leases
+    }
+
+    fn insert_leases(&mut self, leases: &[Lease]) -> StoreResult<()> {
+        let tx = self.conn().transaction()?;
+        for lease in leases {
+            db::save_lease(&tx, lease)?;
+            db::record_history(&tx, HistoryKind::Allocated, lease, None, lease.allocated_at)?;
+        }
+        tx.commit()?;
+
+        for lease in leases {
+            self.leases.insert(lease.port, lease.clone());
+        }
+        Ok(())
+    }
+
+    fn remove_lease(&mut self, port: u16, kind: HistoryKind, reason: &str, at: DateTime<Utc>) -> StoreResult<Option<Lease>> {
+        let Some(lease) = self.leases.get(&port).cloned() else {
+            return Ok(None);
+        };
+        let tx = self.conn().transaction()?;
+        db::delete_lease(&tx, port)?;
+        db::record_history(&tx, kind, &lease, Some(reason), at)?;
+        tx.commit()?;
+        Ok(self.leases.remove(&port))
+    }
+
+    fn touch_lease(&mut self, port: u16, at: DateTime<Utc>) -> StoreResult<Option<&Lease>> {
+        if !self.leases.contains_key(&port) {
+            return Ok(None);
+        }
+        db::update_heartbeat(self.conn(), port, at)?;
+        let lease = self.leases.get_mut(&port).unwrap();
+        lease.last_heartbeat = at;
+        Ok(Some(lease))
+    }
+
+    fn sticky_port(&self, namespace: &str, service_name: &str) -> Option<u16> {
+        self.sticky_ports.get(&(namespace.to_string(), service_name.to_string())).copied()
+    }
+
+    fn set_sticky_port(&mut self, namespace: &str, service_name: &str, port: u16, at: DateTime<Utc>) -> StoreResult<()> {
+        db::save_sticky_port(self.conn(), namespace, service_name, port, at)?;
+        self.sticky_ports.insert((namespace.to_string(), service_name.to_string()), port);
+        Ok(())
+    }
+
+    fn sticky_ports(&self) -> &HashMap<(String, String), u16> {
+        &self.sticky_ports
+    }
+
+    fn reservations(&self) -> &HashMap<u16, Reservation> {
+        &self.reservations
+    }
+
+    fn insert_reservation(&mut self, reservation: Reservation) -> StoreResult<()> {
+        db::save_reservation(self.conn(), &reservation)?;
+        self.reservations.insert(reservation.port, reservation);
+        Ok(())
+    }
+
+    fn remove_reservation(&mut self, port: u16) -> StoreResult<Option<Reservation>> {
+        db::delete_reservation(self.conn(), port)?;
+        Ok(self.reservations.remove(&port))
+    }
+
+    fn tokens(&self) -> &HashMap<String, ApiToken> {
+        &self.tokens
+    }
+
+    fn insert_token(&mut self, token: ApiToken, secret_hash: String) -> StoreResult<()> {
+        db::save_token(self.conn(), &token, &secret_hash)?;
+        self.tokens.insert(secret_hash, token);
+        Ok(())
+    }
+
+    fn remove_token(&mut self, id: &str) -> StoreResult<Option<ApiToken>> {
+        db::delete_token(self.conn(), id)?;
+        let hash = self.tokens.iter().find(|(_, t)| t.id == id).map(|(hash, _)| hash.clone());
+        Ok(hash.and_then(|hash| self.tokens.remove(&hash)))
+    }
+
+    fn import(&mut self, batch: &ImportBatch, at: DateTime<Utc>) -> StoreResult<Vec<Lease>> {
+        let removed: Vec<Lease> = if batch.replace { self.leases.values().cloned().collect() } else { Vec::new() };
+        let tx = self.conn().transaction()?;
+        if batch.replace {
+            db::clear_state(&tx)?;
+            for lease in &removed {
+                db::record_history(&tx, HistoryKind::Released, lease, Some(REPLACED_BY_IMPORT), at)?;
+            }
+        }
+        for reservation in &batch.reservations {
+            db::save_reservation(&tx, reservation)?;
+        }
+        for lease in &batch.leases {
+            db::save_lease(&tx, lease)?;
+            db::record_history(&tx, HistoryKind::Allocated, lease, None, lease.allocated_at)?;
+        }
+        for sticky in &batch.sticky_ports {
+            db::save_sticky_port(&tx, &sticky.namespace, &sticky.service_name, sticky.port, at)?;
+        }
+        tx.commit()?;
+
+        if batch.replace {
+            self.leases.clear();
+            self.reservations.clear();
+            self.sticky_ports.clear();
+        }
+        for reservation in &batch.reservations {
+            self.reservations.insert(reservation.port, reservation.clone());
+        }
+        for lease in &batch.leases {
+            self.leases.insert(lease.port, lease.clone());
+        }
+        for sticky in &batch.sticky_ports {
+            self.sticky_ports.insert((sticky.namespace.clone(), sticky.service_name.clone()), sticky.port);
+        }
+        Ok(removed)
+    }
+
+    fn pools(&self) -> StoreResult<Vec<Pool>> {
+        Ok(db::load_pools(&self.conn.lock().unwrap())?)
+    }
+
+    fn save_pool(&mut self, pool: &Pool) -> StoreResult<()> {
+        Ok(db::save_pool(self.conn(), pool)?)
+    }
+
+    fn delete_pool(&mut self, name: &str) -> StoreResult<()> {
+        db::delete_pool(self.conn(), name)?;
+        Ok(())
+    }
+
+    fn record_history(&mut self, kind: HistoryKind, lease: &Lease, reason: Option<&str>, at: DateTime<Utc>) -> StoreResult<()> {
+        Ok(db::record_history(self.conn(), kind, lease, reason, at)?)
+    }
+
+    fn history(&self, query: &HistoryQuery) -> StoreResult<Vec<HistoryEntry>> {
+        Ok(db::load_history(&self.conn.lock().unwrap(), query)?)
+    }
+
+    fn prune_history(&mut self, before: DateTime<Utc>) -> StoreResult<usize> {
+        Ok(db::prune_history(self.conn(), before)?)
+    }
+}
+
+/// Store that keeps everything in memory and loses it on exit.
+/// Meant for tests and throwaway daemons, e.g. in CI.
+#[derive(Default)]
+pub struct MemoryStore {
+    leases: HashMap<u16, Lease>,
+    sticky_ports: HashMap<(String, String), u16>,
+    reservations: HashMap<u16, Reservation>,
+    pools: HashMap<String, Pool>,
+    tokens: HashMap<String, ApiToken>,
+    history: Vec<HistoryEntry>,
+    next_history_id: i64,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LeaseStore for MemoryStore {
+    fn leases(&self) -> &HashMap<u16, Lease> {
+        &self.leases
+    }
+
+    fn insert_leases(&mut self, leases: &[Lease]) -> StoreResult<()> {
+        for lease in leases {
+            self.leases.insert(lease.port, lease.clone());
+            self.record_history(HistoryKind::Allocated, lease, None, lease.allocated_at)?;
+        }
+        Ok(())
+    }
+
+    fn remove_lease(&mut self, port: u16, kind: HistoryKind, reason: &str, at: DateTime<Utc>) -> StoreResult<Option<Lease>> {
+        let lease = self.leases.remove(&port);
+        if let Some(lease) = &lease {
+            self.record_history(kind, lease, Some(reason), at)?;
+        }
+        Ok(lease)
+    }
+
+    fn touch_lease(&mut self, port: u16, at: DateTime<Utc>) -> StoreResult<Option<&Lease>> {
+        Ok(self.leases.get_mut(&port).map(|lease| {
+            lease.last_heartbeat = at;
+            &*lease
+        }))
+    }
+
+    fn sticky_port(&self, namespace: &str, service_name: &str) -> Option<u16> {
+        self.sticky_ports.get(&(namespace.to_string(), service_name.to_string())).copied()
+    }
+
+    fn set_sticky_port(&mut self, namespace: &str, service_name: &str, port: u16, _at: DateTime<Utc>) -> StoreResult<()> {
+        self.sticky_ports.insert((namespace.to_string(), service_name.to_string()), port);
+        Ok(())
+    }
+
+    fn sticky_ports(&self) -> &HashMap<(String, String), u16> {
+        &self.sticky_ports
+    }
+
+    fn reservations(&self) -> &HashMap<u16, Reservation> {
+        &self.reservations
+    }
+
+    fn insert_reservation(&mut self, reservation: Reservation) -> StoreResult<()> {
+        self.reservations.insert(reservation.port, reservation);
+        Ok(())
+    }
+
+    fn remove_reservation(&mut self, port: u16) -> StoreResult<Option<Reservation>> {
+        Ok(self.reservations.remove(&port))
+    }
+
+    fn tokens(&self) -> &HashMap<String, ApiToken> {
+        &self.tokens
+    }
+
+    fn insert_token(&mut self, token: ApiToken, secret_hash: String) -> StoreResult<()> {
+        self.tokens.insert(secret_hash, token);
+        Ok(())
+    }
+
+    fn remove_token(&mut self, id: &str) -> StoreResult<Option<ApiToken>> {
+        let hash = self.tokens.iter().find(|(_, t)| t.id == id).map(|(hash, _)| hash.clone());
+        Ok(hash.and_then(|hash| self.tokens.remove(&hash)))
+    }
+
+    fn import(&mut self, batch: &ImportBatch, at: DateTime<Utc>) -> StoreResult<Vec<Lease>> {
+        let mut removed = Vec::new();
+        if batch.replace {
+            removed = self.leases.drain().map(|(_, lease)| lease).collect();
+            self.reservations.clear();
+            self.sticky_ports.clear();
+            for lease in &removed {
+                self.record_history(HistoryKind::Released, lease, Some(REPLACED_BY_IMPORT), at)?;
+            }
+        }
+        for reservation in &batch.reservations {
+            self.reservations.insert(reservation.port, reservation.clone());
+        }
+        for lease in &batch.leases {
+            self.leases.insert(lease.port, lease.clone());
+            self.record_history(HistoryKind::Allocated, lease, None, lease.allocated_at)?;
+        }
+        for sticky in &batch.sticky_ports {
+            self.sticky_ports.insert((sticky.namespace.clone(), sticky.service_name.clone()), sticky.port);
+        }
+        Ok(removed)
+    }
+
+    fn pools(&self) -> StoreResult<Vec<Pool>> {
+        Ok(self.pools.values().cloned().collect())
+    }
+
+    fn save_pool(&mut self, pool: &Pool) -> StoreResult<()> {
+        self.pools.insert(pool.name.clone(), pool.clone());
+        Ok(())
+    }
+
+    fn delete_pool(&mut self, name: &str) -> StoreResult<()> {
+        self.pools.remove(name);
+        Ok(())
+    }
+
+    fn record_history(&mut self, kind: HistoryKind, lease: &Lease, reason: Option<&str>, at: DateTime<Utc>) -> StoreResult<()> {
+        self.next_history_id += 1;
+        self.history.push(HistoryEntry {
+            id: self.next_history_id,
+            port: lease.port,
+            service_name: lease.service_name.clone(),
+            namespace: lease.namespace.clone(),
+            kind,
+            reason: reason.map(str::to_string),
+            at,
+        });
+        Ok(())
+    }
+
+    fn history(&self, query: &HistoryQuery) -> StoreResult<Vec<HistoryEntry>> {
+        Ok(self
+            .history
+            .iter()
+            .rev()
+            .filter(|e| query.service.as_ref().is_none_or(|s| e.service_name == *s))
+            .filter(|e| query.port.is_none_or(|p| e.port == p))
+            .filter(|e| query.namespace.as_ref().is_none_or(|n| e.namespace == *n))
+            .filter(|e| query.since.is_none_or(|since| e.at >= since))
+            .take(query.limit.unwrap_or(100) as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn prune_history(&mut self, before: DateTime<Utc>) -> StoreResult<usize> {
+        let len = self.history.len();
+        self.history.retain(|e| e.at >= before);
+        Ok(len - self.history.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use common::{AllocationStrategy, TokenScope};
+
+    fn lease(port: u16, service_name: &str) -> Lease {
+        let now = Utc::now();
+        Lease {
+            port,
+            service_name: service_name.to_string(),
+            allocated_at: now,
+            last_heartbeat: now,
+            ttl_seconds: 60,
+            tags: vec!["test".to_string()],
+            owner_pid: None,
+            owner_start_time: None,
+            group_id: None,
+            role: None,
+            pool: "default".to_string(),
+            namespace: "default".to_string(),
+        }
+    }
+
+    fn leases_round_trip(store: &mut dyn LeaseStore) {
+        store.insert_leases(&[lease(8001, "api"), lease(8002, "db")]).unwrap();
+        assert_eq!(store.leases().len(), 2);
+        assert_eq!(store.leases()[&8001].service_name, "api");
+        assert_eq!(store.leases()[&8001].tags, vec!["test".to_string()]);
+
+        let later = Utc::now() + Duration::seconds(30);
+        assert_eq!(store.touch_lease(8002, later).unwrap().unwrap().last_heartbeat, later);
+        assert!(store.touch_lease(9999, later).unwrap().is_none());
+
+        assert_eq!(store.remove_lease(8001, HistoryKind::Released, "done", later).unwrap().unwrap().port, 8001);
+        assert!(store.remove_lease(8001, HistoryKind::Released, "done", later).unwrap().is_none());
+        assert_eq!(store.leases().keys().copied().collect::<Vec<_>>(), vec![8002]);
+    }
+
+    fn sticky_ports_and_reservations(store: &mut dyn LeaseStore) {
+        store.set_sticky_port("default", "api", 8001, Utc::now()).unwrap();
+        store.set_sticky_port("default", "api", 8005, Utc::now()).unwrap();
+        assert_eq!(store.sticky_port("default", "api"), Some(8005));
+        assert_eq!(store.sticky_ports().len(), 1);
+        // Another namespace's service of the same name has a port of its own
+        assert_eq!(store.sticky_port("alice", "api"), None);
+        store.set_sticky_port("alice", "api", 8009, Utc::now()).unwrap();
+        assert_eq!(store.sticky_port("alice", "api"), Some(8009));
+        assert_eq!(store.sticky_port("default", "api"), Some(8005));
+
+        let reservation = Reservation { port: 8080, service_name: "proxy".to_string(), reserved_at: Utc::now() };
+        store.insert_reservation(reservation).unwrap();
+        assert_eq!(store.reservations()[&8080].service_name, "proxy");
+        assert_eq!(store.remove_reservation(8080).unwrap().unwrap().service_name, "proxy");
+        assert!(store.remove_reservation(8080).unwrap().is_none());
+    }
+
+    fn pools_and_tokens(store: &mut dyn LeaseStore) {
+        let pool = Pool {
+            name: "db".to_string(),
+            min_port: 15000,
+            max_port: 15009,
+            default_ttl: 120,
+            strategy: AllocationStrategy::LastFree,
+        };
+        store.save_pool(&pool).unwrap();
+        store.save_pool(&Pool { max_port: 15019, ..pool }).unwrap();
+        let pools = store.pools().unwrap();
+        assert_eq!(pools.len(), 1);
+        assert_eq!((pools[0].min_port, pools[0].max_port), (15000, 15019));
+        assert!(matches!(pools[0].strategy, AllocationStrategy::LastFree));
+        store.delete_pool("db").unwrap();
+        assert!(store.pools().unwrap().is_empty());
+
+        let token = ApiToken {
+            id: "3f1a9b0c2d4e".to_string(),
+            name: "ci".to_string(),
+            namespace: "ci".to_string(),
+            scope: TokenScope::Allocate,
+            created_at: Utc::now(),
+        };
+        store.insert_token(token, "hash".to_string()).unwrap();
+        assert_eq!(store.tokens()["hash"].name, "ci");
+        assert_eq!(store.remove_token("3f1a9b0c2d4e").unwrap().unwrap().name, "ci");
+        assert!(store.remove_token("3f1a9b0c2d4e").unwrap().is_none());
+        assert!(store.tokens().is_empty());
+    }
+
+    fn history_filters_newest_first(store: &mut dyn LeaseStore) {
+        let start = Utc::now() - Duration::hours(2);
+        let api = lease(8001, "api");
+        let db = lease(8002, "db");
+        store.record_history(HistoryKind::Allocated, &api, None, start).unwrap();
+        store.record_history(HistoryKind::Allocated, &db, None, start + Duration::minutes(1)).unwrap();
+        store.record_history(HistoryKind::Expired, &api, Some("ttl"), start + Duration::hours(1)).unwrap();
+
+        let all = store.history(&HistoryQuery::default()).unwrap();
+        let kinds: Vec<HistoryKind> = all.iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![HistoryKind::Expired, HistoryKind::Allocated, HistoryKind::Allocated]);
+        assert_eq!(all[0].reason.as_deref(), Some("ttl"));
+
+        let by_service = store.history(&HistoryQuery { service: Some("api".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(by_service.len(), 2);
+        let by_port = store.history(&HistoryQuery { port: Some(8002), ..Default::default() }).unwrap();
+        assert_eq!(by_port.len(), 1);
+        let since = store.history(&HistoryQuery { since: Some(start + Duration::minutes(30)), ..Default::default() }).unwrap();
+        assert_eq!(since.len(), 1);
+        let limited = store.history(&HistoryQuery { limit: Some(1), ..Default::default() }).unwrap();
+        assert_eq!(limited[0].kind, HistoryKind::Expired);
+
+        let theirs = Lease { namespace: "alice".to_string(), ..lease(8003, "api") };
+        store.record_history(HistoryKind::Allocated, &theirs, None, start + Duration::hours(1)).unwrap();
+        let by_namespace = store.history(&HistoryQuery { namespace: Some("alice".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(by_namespace.iter().map(|e| e.port).collect::<Vec<_>>(), vec![8003]);
+        assert_eq!(by_namespace[0].namespace, "alice");
+        let ours = store.history(&HistoryQuery { namespace: Some("default".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(ours.len(), 3);
+
+        assert_eq!(store.prune_history(start + Duration::minutes(30)).unwrap(), 2);
+        assert_eq!(store.history(&HistoryQuery::default()).unwrap().len(), 2);
+    }
+
+    fn inserting_leases_records_allocations(store: &mut dyn LeaseStore) {
+        store.insert_leases(&[lease(8001, "api")]).unwrap();
+        let history = store.history(&HistoryQuery::default()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].port, history[0].kind), (8001, HistoryKind::Allocated));
+    }
+
+    fn removing_leases_records_why(store: &mut dyn LeaseStore) {
+        store.insert_leases(&[lease(8001, "api"), lease(8002, "db")]).unwrap();
+        let now = Utc::now();
+        store.remove_lease(8001, HistoryKind::Expired, "no heartbeat", now).unwrap();
+        store.remove_lease(8002, HistoryKind::Released, "released by admin", now).unwrap();
+        store.remove_lease(8003, HistoryKind::Released, "released by admin", now).unwrap();
+
+        let removals: Vec<(u16, HistoryKind, Option<String>)> = store
+            .history(&HistoryQuery::default())
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.kind != HistoryKind::Allocated)
+            .map(|e| (e.port, e.kind, e.reason))
+            .collect();
+        assert_eq!(removals.len(), 2);
+        assert!(removals.contains(&(8001, HistoryKind::Expired, Some("no heartbeat".to_string()))));
+        assert!(removals.contains(&(8002, HistoryKind::Released, Some("released by admin".to_string()))));
+    }
+
+    fn import_replaces_state(store: &mut dyn LeaseStore) {
+        store.insert_leases(&[lease(8001, "api")]).unwrap();
+        store.set_sticky_port("default", "api", 8001, Utc::now()).unwrap();
+        store.insert_reservation(Reservation { port: 8080, service_name: "proxy".to_string(), reserved_at: Utc::now() }).unwrap();
+
+        let merge = ImportBatch { leases: vec![lease(8002, "db")], ..Default::default() };
+        assert!(store.import(&merge, Utc::now()).unwrap().is_empty());
+        assert_eq!(store.leases().len(), 2);
+
+        let replace = ImportBatch {
+            replace: true,
+            leases: vec![lease(8003, "web")],
+            reservations: Vec::new(),
+            sticky_ports: vec![StickyPort { service_name: "web".to_string(), port: 8003, namespace: "alice".to_string() }],
+        };
+        let mut removed: Vec<u16> = store.import(&replace, Utc::now()).unwrap().iter().map(|l| l.port).collect();
+        removed.sort();
+        assert_eq!(removed, vec![8001, 8002]);
+        assert_eq!(store.leases().keys().copied().collect::<Vec<_>>(), vec![8003]);
+        assert!(store.reservations().is_empty());
+        assert_eq!(store.sticky_port("default", "api"), None);
+        assert_eq!(store.sticky_port("alice", "web"), Some(8003));
+
+        let released = store.history(&HistoryQuery { port: Some(8001), ..Default::default() }).unwrap();
+        assert_eq!(released[0].kind, HistoryKind::Released);
+        assert_eq!(released[0].reason.as_deref(), Some(REPLACED_BY_IMPORT));
+    }
+
+    /// Run every case against both backends, so they cannot drift apart.
+    macro_rules! store_tests {
+        ($($case:ident),* $(,)?) => {
+            mod memory {
+                $(#[test] fn $case() { super::$case(&mut super::MemoryStore::new()) })*
+            }
+            mod sqlite {
+                $(#[test] fn $case() { super::$case(&mut super::SqliteStore::open_in_memory()) })*
+            }
+        };
+    }
+
+    store_tests!(
+        leases_round_trip,
+        sticky_ports_and_reservations,
+        pools_and_tokens,
+        history_filters_newest_first,
+        inserting_leases_records_allocations,
+        removing_leases_records_why,
+        import_replaces_state,
+    );
+}
@@ -1,10 +1,43 @@
-use common::{AllocateRequest, AllocateResponse, ReleaseRequest};
-use reqwest::Client;
+use common::{
+    AllocateRequest, AllocateResponse, AllocationStrategy, ApiError, Conflict, ErrorCode, EventKind, HistoryEntry,
+    HistoryKind, HistoryQuery, ImportItem, ImportReport, Lease, LeaseEvent, LookupResponse, PoolSpec, ReleaseRequest,
+    ReserveRequest, SkipReason, StateExport, CreateTokenRequest, CreatedToken, TokenScope,
+};
+use portmanager::PortManagerClient;
 use std::time::Duration;
 use tokio::time::sleep;
+use reqwest::Client;
 
 const BASE_URL: &str = "http://localhost:3030";
 
+/// Client that authenticates with a fresh admin token. Anonymous TCP callers
+/// may only allocate, so the token is created over the daemon's Unix socket,
+/// where the daemon's own user is an admin. `None` if the socket is unreachable.
+async fn admin_client() -> Option<Client> {
+    socket_request(reqwest::Method::POST, "/tokens")?
+        .json(&CreateTokenRequest { name: "integration-admin".to_string(), scope: TokenScope::Admin, namespace: None })
+        .send()
+        .await
+        .ok()?
+        .json::<CreatedToken>()
+        .await
+        .ok()
+        .map(|created| token_client(&created.secret))
+}
+
+/// TCP client that sends `secret` as its API token.
+fn token_client(secret: &str) -> Client {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(reqwest::header::AUTHORIZATION, format!("Bearer {}", secret).parse().unwrap());
+    Client::builder().default_headers(headers).build().unwrap()
+}
+
+/// Request to `path` under /v1 on the daemon's Unix socket, as the daemon's own user.
+fn socket_request(method: reqwest::Method, path: &str) -> Option<reqwest::RequestBuilder> {
+    let socket = Client::builder().unix_socket(common::default_socket_path()).build().ok()?;
+    Some(socket.request(method, format!("http://localhost/v1{}", path)))
+}
+
 // Note: Ensure the daemon is running before running this test, 
 // or implement a spawning mechanism directly in the test setup.
 // For simplicity in this environment, this test acts as a client integration test.
@@ -18,6 +51,7 @@ async fn test_full_lifecycle() {
         service_name: "integration-test-service".to_string(),
         ttl_seconds: Some(60),
         tags: Some(vec!["test".to_string()]),
+        ..Default::default()
     };
 
     let resp = client.post(format!("{}/alloc", BASE_URL))
@@ -46,7 +80,7 @@ async fn test_full_lifecycle() {
     // We could parse and check if our port is there
 
     // 3. Release
-    let release_req = ReleaseRequest { port: alloc_resp.port };
+    let release_req = ReleaseRequest { port: Some(alloc_resp.port), ..Default::default() };
     let rel_resp = client.post(format!("{}/release", BASE_URL))
         .json(&release_req)
         .send()
@@ -55,3 +89,1035 @@ async fn test_full_lifecycle() {
     
     assert!(rel_resp.status().is_success());
 }
+
+#[tokio::test]
+async fn test_sticky_port_reused_after_release() {
+    let client = Client::new();
+
+    let alloc_req = AllocateRequest {
+        service_name: "integration-sticky-service".to_string(),
+        ttl_seconds: Some(60),
+        ..Default::default()
+    };
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&alloc_req)
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+
+    if !resp.status().is_success() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    let first: AllocateResponse = resp.json().await.unwrap();
+    client.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { port: Some(first.port), ..Default::default() })
+        .send()
+        .await
+        .expect("Failed to release");
+
+    // Same service should get the same port back
+    let second: AllocateResponse = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&alloc_req)
+        .send()
+        .await
+        .expect("Failed to send alloc request")
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(first.port, second.port);
+
+    client.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { port: Some(second.port), ..Default::default() })
+        .send()
+        .await
+        .expect("Failed to release");
+}
+
+#[tokio::test]
+async fn test_skips_port_in_use_by_unmanaged_process() {
+    let client = Client::new();
+
+    // Occupy a port in the default range outside of PortManager
+    let squatter = match std::net::TcpListener::bind("127.0.0.1:8990") {
+        Ok(listener) => listener,
+        Err(_) => {
+            println!("Port 8990 unavailable. Skipping integration test assertions.");
+            return;
+        }
+    };
+
+    let alloc_req = AllocateRequest {
+        service_name: "integration-probe-service".to_string(),
+        ttl_seconds: Some(60),
+        preferred_port: Some(8990),
+        ..Default::default()
+    };
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&alloc_req)
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+
+    if !resp.status().is_success() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    let alloc_resp: AllocateResponse = resp.json().await.unwrap();
+    assert_ne!(alloc_resp.port, 8990);
+    assert!(alloc_resp.skipped.iter().any(|s| s.port == 8990 && s.reason == SkipReason::InUse));
+
+    drop(squatter);
+    client.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { port: Some(alloc_resp.port), ..Default::default() })
+        .send()
+        .await
+        .expect("Failed to release");
+}
+
+#[tokio::test]
+async fn test_conflicts_reports_unmanaged_listener() {
+    let client = Client::new();
+
+    let squatter = match std::net::TcpListener::bind("127.0.0.1:8991") {
+        Ok(listener) => listener,
+        Err(_) => {
+            println!("Port 8991 unavailable. Skipping integration test assertions.");
+            return;
+        }
+    };
+
+    let resp = client.get(format!("{}/v1/conflicts?refresh=true", BASE_URL))
+        .send()
+        .await
+        .expect("Failed to get conflicts");
+
+    if !resp.status().is_success() || !std::path::Path::new("/proc/net/tcp").exists() {
+        println!("Daemon might not be running or no procfs. Skipping integration test assertions.");
+        return;
+    }
+
+    let conflicts: Vec<Conflict> = resp.json().await.unwrap();
+    let conflict = conflicts.iter().find(|c| c.port == 8991).expect("squatter not reported");
+    assert_eq!(conflict.pid, Some(std::process::id()));
+
+    drop(squatter);
+}
+
+#[tokio::test]
+async fn test_lease_released_when_owner_exits() {
+    let client = Client::new();
+
+    let mut child = std::process::Command::new("sleep")
+        .arg("1")
+        .spawn()
+        .expect("Failed to spawn owner process");
+
+    let alloc_req = AllocateRequest {
+        service_name: "integration-owner-service".to_string(),
+        ttl_seconds: Some(600),
+        owner_pid: Some(child.id()),
+        ..Default::default()
+    };
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&alloc_req)
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+
+    child.wait().unwrap();
+    if !resp.status().is_success() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    let alloc_resp: AllocateResponse = resp.json().await.unwrap();
+    assert_eq!(alloc_resp.lease.owner_pid, alloc_req.owner_pid);
+
+    // The cleaner runs every 10 seconds; the lease must go long before its TTL
+    for _ in 0..30 {
+        sleep(Duration::from_millis(500)).await;
+        let leases: Vec<Lease> = client.get(format!("{}/v1/list", BASE_URL))
+            .send()
+            .await
+            .expect("Failed to get list")
+            .json()
+            .await
+            .unwrap();
+        if !leases.iter().any(|l| l.port == alloc_resp.port) {
+            return;
+        }
+    }
+    panic!("Lease for port {} outlived its owner process", alloc_resp.port);
+}
+
+#[tokio::test]
+async fn test_lease_released_when_owner_is_zombie() {
+    let client = Client::new();
+
+    // Never waited on until the end, so it lingers as a zombie once it exits
+    let mut child = std::process::Command::new("true")
+        .spawn()
+        .expect("Failed to spawn owner process");
+
+    let alloc_req = AllocateRequest {
+        service_name: "integration-zombie-owner-service".to_string(),
+        ttl_seconds: Some(600),
+        owner_pid: Some(child.id()),
+        ..Default::default()
+    };
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&alloc_req)
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+
+    if !resp.status().is_success() {
+        child.wait().unwrap();
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    let alloc_resp: AllocateResponse = resp.json().await.unwrap();
+    let mut released = false;
+    for _ in 0..30 {
+        sleep(Duration::from_millis(500)).await;
+        let leases: Vec<Lease> = client.get(format!("{}/v1/list", BASE_URL))
+            .send()
+            .await
+            .expect("Failed to get list")
+            .json()
+            .await
+            .unwrap();
+        if !leases.iter().any(|l| l.port == alloc_resp.port) {
+            released = true;
+            break;
+        }
+    }
+    child.wait().unwrap();
+    assert!(released, "Lease for port {} outlived its zombie owner", alloc_resp.port);
+}
+
+#[tokio::test]
+async fn test_contiguous_group_allocation_and_release() {
+    let client = Client::new();
+
+    let alloc_req = AllocateRequest {
+        service_name: "integration-group-service".to_string(),
+        ttl_seconds: Some(60),
+        contiguous: true,
+        roles: Some(vec!["http".to_string(), "grpc".to_string(), "metrics".to_string()]),
+        ..Default::default()
+    };
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&alloc_req)
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+
+    if !resp.status().is_success() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    let alloc_resp: AllocateResponse = resp.json().await.unwrap();
+    let group_id = alloc_resp.group_id.clone().expect("group id missing");
+    let ports: Vec<u16> = alloc_resp.leases.iter().map(|l| l.port).collect();
+    assert_eq!(ports, vec![alloc_resp.port, alloc_resp.port + 1, alloc_resp.port + 2]);
+    assert_eq!(alloc_resp.leases[1].role.as_deref(), Some("grpc"));
+
+    // Releasing the group frees every port in it
+    let rel_resp = client.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { group_id: Some(group_id), ..Default::default() })
+        .send()
+        .await
+        .expect("Failed to release");
+    assert!(rel_resp.status().is_success());
+
+    let leases: Vec<Lease> = client.get(format!("{}/v1/list", BASE_URL))
+        .send()
+        .await
+        .expect("Failed to get list")
+        .json()
+        .await
+        .unwrap();
+    assert!(!leases.iter().any(|l| ports.contains(&l.port)));
+}
+
+#[tokio::test]
+async fn test_named_pool_allocation_and_filter() {
+    let client = Client::new();
+    let Some(admin) = admin_client().await else {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    };
+
+    let spec = PoolSpec {
+        min_port: 15000,
+        max_port: 15009,
+        default_ttl: Some(120),
+        strategy: AllocationStrategy::LastFree,
+    };
+    let resp = admin.put(format!("{}/v1/pools/integration-db", BASE_URL))
+        .json(&spec)
+        .send()
+        .await
+        .expect("Failed to send pool request");
+
+    if !resp.status().is_success() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    let alloc_req = AllocateRequest {
+        service_name: "integration-pool-service".to_string(),
+        pool: Some("integration-db".to_string()),
+        ..Default::default()
+    };
+    let alloc_resp: AllocateResponse = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&alloc_req)
+        .send()
+        .await
+        .expect("Failed to send alloc request")
+        .json()
+        .await
+        .unwrap();
+    assert!((15000..=15009).contains(&alloc_resp.port));
+    assert_eq!(alloc_resp.lease.ttl_seconds, 120);
+    assert_eq!(alloc_resp.lease.pool, "integration-db");
+
+    let leases: Vec<Lease> = client.get(format!("{}/v1/list?pool=integration-db", BASE_URL))
+        .send()
+        .await
+        .expect("Failed to get list")
+        .json()
+        .await
+        .unwrap();
+    assert!(leases.iter().all(|l| l.pool == "integration-db"));
+    assert!(leases.iter().any(|l| l.port == alloc_resp.port));
+
+    // A pool with active leases cannot be deleted
+    let del_resp = admin.delete(format!("{}/v1/pools/integration-db", BASE_URL))
+        .send()
+        .await
+        .expect("Failed to delete pool");
+    assert_eq!(del_resp.status(), reqwest::StatusCode::CONFLICT);
+
+    client.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { port: Some(alloc_resp.port), ..Default::default() })
+        .send()
+        .await
+        .expect("Failed to release");
+    let del_resp = admin.delete(format!("{}/v1/pools/integration-db", BASE_URL))
+        .send()
+        .await
+        .expect("Failed to delete pool");
+    assert!(del_resp.status().is_success());
+
+    // Port 0 means "any port" to the OS and can never be leased
+    let resp = admin.put(format!("{}/v1/pools/integration-zero", BASE_URL))
+        .json(&PoolSpec { min_port: 0, ..spec })
+        .send()
+        .await
+        .expect("Failed to send pool request");
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    let error: ApiError = resp.json().await.unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidRequest);
+}
+
+#[tokio::test]
+async fn test_reserved_port_only_goes_to_its_service() {
+    let client = Client::new();
+    let Some(admin) = admin_client().await else {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    };
+
+    let reserve_req = ReserveRequest {
+        port: 8995,
+        service_name: "integration-reserved-owner".to_string(),
+    };
+    let resp = admin.post(format!("{}/v1/reservations", BASE_URL))
+        .json(&reserve_req)
+        .send()
+        .await
+        .expect("Failed to send reservation request");
+
+    if !resp.status().is_success() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    // Another service asking for the port is turned away
+    let other: AllocateResponse = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&AllocateRequest {
+            service_name: "integration-reserved-other".to_string(),
+            preferred_port: Some(8995),
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to send alloc request")
+        .json()
+        .await
+        .unwrap();
+    assert_ne!(other.port, 8995);
+    assert!(other.skipped.iter().any(|s| s.port == 8995 && s.reason == SkipReason::Reserved));
+
+    // The owner gets it without asking
+    let owner: AllocateResponse = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&AllocateRequest {
+            service_name: "integration-reserved-owner".to_string(),
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to send alloc request")
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(owner.port, 8995);
+
+    for port in [other.port, owner.port] {
+        client.post(format!("{}/v1/release", BASE_URL))
+            .json(&ReleaseRequest { port: Some(port), ..Default::default() })
+            .send()
+            .await
+            .expect("Failed to release");
+    }
+    admin.delete(format!("{}/v1/reservations/8995", BASE_URL))
+        .send()
+        .await
+        .expect("Failed to remove reservation");
+}
+
+#[tokio::test]
+async fn test_errors_are_structured_json() {
+    let client = Client::new();
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&AllocateRequest {
+            service_name: "integration-error-service".to_string(),
+            pool: Some("integration-no-such-pool".to_string()),
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+
+    if resp.status().is_server_error() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+    let error: ApiError = resp.json().await.unwrap();
+    assert_eq!(error.code, ErrorCode::PoolNotFound);
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&AllocateRequest {
+            service_name: "has whitespace".to_string(),
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    let error: ApiError = resp.json().await.unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidServiceName);
+
+    let resp = client.post(format!("{}/v1/release", BASE_URL))
+        .body("not json")
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("Failed to send release request");
+    let error: ApiError = resp.json().await.unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidRequest);
+}
+
+#[tokio::test]
+async fn test_v1_api_and_deprecated_aliases() {
+    let client = Client::new();
+
+    let resp = match client.get(format!("{}/v1/list", BASE_URL)).send().await {
+        Ok(resp) => resp,
+        Err(_) => {
+            println!("Daemon might not be running. Skipping integration test assertions.");
+            return;
+        }
+    };
+    assert!(resp.status().is_success());
+    assert!(resp.headers().get("deprecation").is_none());
+
+    let resp = client.get(format!("{}/list", BASE_URL)).send().await.unwrap();
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get("deprecation").unwrap(), "true");
+
+    // Only the endpoints that predate /v1 have unversioned aliases
+    let resp = client.get(format!("{}/lookup?service=integration-no-such-service", BASE_URL)).send().await.unwrap();
+    assert_eq!(resp.headers().get("deprecation").unwrap(), "true");
+
+    // Typos in API paths and unversioned newer endpoints are 404s, not the dashboard
+    for path in ["/v1/lsit", "/lsit", "/pools", "/history"] {
+        let resp = client.get(format!("{}{}", BASE_URL, path)).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND, "{}", path);
+        let error: ApiError = resp.json().await.unwrap();
+        assert_eq!(error.code, ErrorCode::NotFound);
+    }
+
+    let spec: serde_json::Value = client.get(format!("{}/v1/openapi.json", BASE_URL))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(spec["paths"]["/v1/alloc"]["post"].is_object());
+    assert!(spec["components"]["schemas"]["AllocateRequest"].is_object());
+}
+
+#[tokio::test]
+async fn test_event_stream_reports_lease_changes() {
+    let client = Client::new();
+    let service_name = "integration-events-service";
+
+    let mut events = match client.get(format!("{}/v1/events?service={}", BASE_URL, service_name)).send().await {
+        Ok(resp) if resp.status().is_success() => resp,
+        _ => {
+            println!("Daemon might not be running. Skipping integration test assertions.");
+            return;
+        }
+    };
+    assert!(events.headers()[reqwest::header::CONTENT_TYPE].to_str().unwrap().starts_with("text/event-stream"));
+
+    // An event for another service must not reach the filtered stream
+    let other: AllocateResponse = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&AllocateRequest { service_name: "integration-events-other".to_string(), ..Default::default() })
+        .send().await.unwrap().json().await.unwrap();
+    let alloc: AllocateResponse = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&AllocateRequest { service_name: service_name.to_string(), ..Default::default() })
+        .send().await.unwrap().json().await.unwrap();
+    for port in [other.port, alloc.port] {
+        client.post(format!("{}/v1/release", BASE_URL))
+            .json(&ReleaseRequest { port: Some(port), group_id: None })
+            .send().await.unwrap();
+    }
+
+    // Collect SSE data lines until both events for our service arrived
+    let mut received: Vec<LeaseEvent> = Vec::new();
+    let mut buffer = String::new();
+    while received.len() < 2 {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), events.chunk())
+            .await
+            .expect("Timed out waiting for events")
+            .unwrap()
+            .expect("Event stream ended");
+        buffer.push_str(&String::from_utf8_lossy(&chunk));
+        while let Some(end) = buffer.find("\n\n") {
+            let frame: String = buffer.drain(..end + 2).collect();
+            if let Some(data) = frame.lines().find_map(|l| l.strip_prefix("data:")) {
+                received.push(serde_json::from_str(data.trim()).unwrap());
+            }
+        }
+    }
+
+    assert_eq!(received[0].kind, EventKind::Allocated);
+    assert_eq!(received[1].kind, EventKind::Released);
+    assert!(received.iter().all(|e| e.port == alloc.port && e.service_name() == Some(service_name)));
+}
+
+#[tokio::test]
+async fn test_event_websocket_rejects_foreign_origin() {
+    let client = Client::new();
+    let upgrade = |origin: &str| {
+        client.get(format!("{}/v1/events", BASE_URL))
+            .header(reqwest::header::CONNECTION, "upgrade")
+            .header(reqwest::header::UPGRADE, "websocket")
+            .header(reqwest::header::SEC_WEBSOCKET_VERSION, "13")
+            .header(reqwest::header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .header(reqwest::header::ORIGIN, origin)
+            .send()
+    };
+
+    let foreign = match upgrade("http://evil.example").await {
+        Ok(resp) => resp,
+        Err(_) => {
+            println!("Daemon might not be running. Skipping integration test assertions.");
+            return;
+        }
+    };
+    assert_eq!(foreign.status(), 403);
+    let error: ApiError = foreign.json().await.unwrap();
+    assert_eq!(error.code, ErrorCode::Forbidden);
+
+    // The dashboard's own origin may still subscribe
+    let dashboard = upgrade(BASE_URL).await.unwrap();
+    assert_eq!(dashboard.status(), reqwest::StatusCode::SWITCHING_PROTOCOLS);
+}
+
+#[tokio::test]
+async fn test_requests_for_foreign_host_are_rejected() {
+    let client = Client::new();
+    // What a DNS-rebinding page sends: the attacker's name, resolved to our address
+    let rebound = match client.get(format!("{}/v1/list", BASE_URL)).header(reqwest::header::HOST, "evil.example:3030").send().await {
+        Ok(resp) => resp,
+        Err(_) => {
+            println!("Daemon might not be running. Skipping integration test assertions.");
+            return;
+        }
+    };
+    assert_eq!(rebound.status(), 403);
+    let error: ApiError = rebound.json().await.unwrap();
+    assert_eq!(error.code, ErrorCode::Forbidden);
+
+    let direct = client.get(format!("{}/v1/list", BASE_URL)).header(reqwest::header::HOST, "127.0.0.1:3030").send().await.unwrap();
+    assert_eq!(direct.status(), 200);
+}
+
+#[tokio::test]
+async fn test_history_records_allocation_and_release() {
+    let client = Client::new();
+    let service_name = "integration-history-service";
+    let since = chrono::Utc::now();
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&AllocateRequest { service_name: service_name.to_string(), ..Default::default() })
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+
+    if resp.status().is_server_error() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    let alloc: AllocateResponse = resp.json().await.unwrap();
+    client.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { port: Some(alloc.port), group_id: None })
+        .send()
+        .await
+        .unwrap();
+
+    let history: Vec<HistoryEntry> = client.get(format!("{}/v1/history", BASE_URL))
+        .query(&HistoryQuery { service: Some(service_name.to_string()), since: Some(since), ..Default::default() })
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Newest first
+    let kinds: Vec<HistoryKind> = history.iter().map(|e| e.kind).collect();
+    assert_eq!(kinds, vec![HistoryKind::Released, HistoryKind::Allocated]);
+    assert!(history.iter().all(|e| e.port == alloc.port));
+    assert_eq!(history[0].reason.as_deref(), Some("released by client"));
+
+    let resp = client.get(format!("{}/v1/history?since=yesterday", BASE_URL)).send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_export_and_merge_import() {
+    let Some(client) = admin_client().await else {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    };
+    let service_name = "integration-export-service";
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&AllocateRequest { service_name: service_name.to_string(), owner_pid: Some(std::process::id()), ..Default::default() })
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+
+    if resp.status().is_server_error() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    let alloc: AllocateResponse = resp.json().await.unwrap();
+    let mut export: StateExport = client.get(format!("{}/v1/export", BASE_URL))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(export.leases.iter().any(|l| l.port == alloc.port && l.service_name == service_name));
+    assert!(export.sticky_ports.iter().any(|s| s.service_name == service_name && s.port == alloc.port && s.namespace == alloc.lease.namespace));
+
+    // Only touch this test's lease; other tests run against the same daemon
+    export.leases.retain(|l| l.service_name == service_name);
+    export.reservations.clear();
+    export.sticky_ports.clear();
+
+    client.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { port: Some(alloc.port), group_id: None })
+        .send()
+        .await
+        .unwrap();
+
+    let import = |export: StateExport| {
+        let client = client.clone();
+        async move {
+            let resp = client.post(format!("{}/v1/import?mode=merge", BASE_URL)).json(&export).send().await.unwrap();
+            assert!(resp.status().is_success());
+            resp.json::<ImportReport>().await.unwrap()
+        }
+    };
+
+    let report = import(export.clone()).await;
+    assert_eq!(report.leases, 1);
+    assert!(report.conflicts.is_empty());
+
+    let leases: Vec<Lease> = client.get(format!("{}/v1/list", BASE_URL)).send().await.unwrap().json().await.unwrap();
+    let restored = leases.iter().find(|l| l.port == alloc.port).expect("Lease not restored");
+    assert_eq!(restored.service_name, service_name);
+    assert_eq!(restored.owner_pid, None);
+
+    // The same lease again is a no-op; the port under another name is a conflict
+    let mut clashing = export.clone();
+    clashing.leases[0].service_name = "integration-export-other".to_string();
+    clashing.leases.extend(export.leases.clone());
+    let report = import(clashing).await;
+    assert_eq!(report.leases, 0);
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(report.conflicts[0].item, ImportItem::Lease);
+    assert_eq!(report.conflicts[0].port, alloc.port);
+
+    client.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { port: Some(alloc.port), group_id: None })
+        .send()
+        .await
+        .unwrap();
+
+    // Leases must fit an existing pool and carry a valid namespace
+    let mut misfits = export.clone();
+    let template = misfits.leases.remove(0);
+    misfits.leases.push(Lease { pool: "integration-no-such-pool".to_string(), ..template.clone() });
+    misfits.leases.push(Lease { port: 1, ..template.clone() });
+    misfits.leases.push(Lease { namespace: String::new(), ..template });
+    let report = import(misfits).await;
+    assert_eq!(report.leases, 0);
+    assert_eq!(report.conflicts.len(), 3);
+    assert!(report.conflicts[0].reason.contains("does not exist"));
+    assert!(report.conflicts[1].reason.contains("outside pool"));
+    assert!(report.conflicts[2].reason.contains("namespace"));
+
+    let mut future = export;
+    future.version = common::EXPORT_VERSION + 1;
+    let resp = client.post(format!("{}/v1/import", BASE_URL)).json(&future).send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_tcp_leases_belong_to_anonymous_namespace() {
+    let client = Client::new();
+    let service_name = "integration-namespace-service";
+
+    let resp = client.post(format!("{}/v1/alloc", BASE_URL))
+        .json(&AllocateRequest { service_name: service_name.to_string(), ..Default::default() })
+        .send()
+        .await
+        .expect("Failed to send alloc request");
+
+    if resp.status().is_server_error() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    // TCP callers cannot be identified; they allocate in a namespace of their own without admin rights
+    let alloc: AllocateResponse = resp.json().await.unwrap();
+    assert_eq!(alloc.lease.namespace, common::ANONYMOUS_NAMESPACE);
+
+    let list = |namespace: &'static str| {
+        let client = client.clone();
+        async move {
+            client.get(format!("{}/v1/list", BASE_URL))
+                .query(&[("namespace", namespace)])
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+    let leases: Vec<Lease> = list(common::ANONYMOUS_NAMESPACE).await.json().await.unwrap();
+    assert!(leases.iter().any(|l| l.port == alloc.port));
+    assert_eq!(list(common::DEFAULT_NAMESPACE).await.status(), 403);
+    assert_eq!(list("integration-someone-else").await.status(), 403);
+
+    for resp in [
+        client.get(format!("{}/v1/export", BASE_URL)).send().await.unwrap(),
+        client.get(format!("{}/v1/tokens", BASE_URL)).send().await.unwrap(),
+        client.delete(format!("{}/v1/reservations/8996", BASE_URL)).send().await.unwrap(),
+    ] {
+        assert_eq!(resp.status(), 403);
+    }
+
+    client.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { port: Some(alloc.port), group_id: None })
+        .send()
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_tcp_caller_cannot_touch_socket_leases() {
+    let request = AllocateRequest { service_name: "integration-socket-owned".to_string(), ..Default::default() };
+    let alloc = match socket_request(reqwest::Method::POST, "/alloc") {
+        Some(request_builder) => request_builder.json(&request).send().await.ok(),
+        None => None,
+    };
+    let Some(alloc) = alloc else {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    };
+    // The daemon's own user, over the socket
+    let alloc: AllocateResponse = alloc.json().await.unwrap();
+    assert_eq!(alloc.lease.namespace, common::DEFAULT_NAMESPACE);
+
+    // Skipping the socket for TCP does not make another local user its owner
+    let client = Client::new();
+    let release = ReleaseRequest { port: Some(alloc.port), group_id: None };
+    let resp = client.post(format!("{}/v1/release", BASE_URL)).json(&release).send().await.unwrap();
+    assert_eq!(resp.status(), 403);
+    let resp = client.post(format!("{}/v1/heartbeat", BASE_URL))
+        .json(&common::HeartbeatRequest { port: Some(alloc.port), group_id: None })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let resp = socket_request(reqwest::Method::POST, "/release").unwrap().json(&release).send().await.unwrap();
+    assert!(resp.status().is_success());
+}
+
+/// Whether the lease on `port` shows up in `/list`, `/lookup` and `/history` for the caller behind `get`.
+async fn visible(get: impl Fn(&str) -> reqwest::RequestBuilder, port: u16, service_name: &str) -> [bool; 3] {
+    let leases: Vec<Lease> = get("/list").send().await.unwrap().json().await.unwrap();
+    let lookup: LookupResponse = get(&format!("/lookup?service={}", service_name)).send().await.unwrap().json().await.unwrap();
+    let history: Vec<HistoryEntry> = get(&format!("/history?service={}", service_name)).send().await.unwrap().json().await.unwrap();
+    [
+        leases.iter().any(|l| l.port == port),
+        lookup.all_ports.contains(&port),
+        history.iter().any(|e| e.port == port),
+    ]
+}
+
+#[tokio::test]
+async fn test_namespaces_are_isolated_in_both_directions() {
+    let anonymous = Client::new();
+    // A tenant with its own namespace, set up by the daemon's own user over the socket
+    let created = match socket_request(reqwest::Method::POST, "/tokens") {
+        Some(request) => request
+            .json(&CreateTokenRequest {
+                name: "integration-tenant".to_string(),
+                scope: TokenScope::Allocate,
+                namespace: Some("integration-tenant".to_string()),
+            })
+            .send()
+            .await
+            .ok(),
+        None => None,
+    };
+    let Some(created) = created else {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    };
+    let tenant = token_client(&created.json::<CreatedToken>().await.unwrap().secret);
+
+    let allocate = |client: &Client, service_name: &str| {
+        client.post(format!("{}/v1/alloc", BASE_URL))
+            .json(&AllocateRequest { service_name: service_name.to_string(), ..Default::default() })
+            .send()
+    };
+    let ours: AllocateResponse = allocate(&anonymous, "integration-isolation-anonymous").await.unwrap().json().await.unwrap();
+    let theirs: AllocateResponse = allocate(&tenant, "integration-isolation-tenant").await.unwrap().json().await.unwrap();
+    assert_eq!(theirs.lease.namespace, "integration-tenant");
+
+    let as_anonymous = |path: &str| anonymous.get(format!("{}/v1{}", BASE_URL, path));
+    let as_tenant = |path: &str| tenant.get(format!("{}/v1{}", BASE_URL, path));
+    let as_socket_admin = |path: &str| socket_request(reqwest::Method::GET, path).unwrap();
+
+    assert_eq!(visible(as_anonymous, ours.port, &ours.lease.service_name).await, [true; 3]);
+    assert_eq!(visible(as_anonymous, theirs.port, &theirs.lease.service_name).await, [false; 3]);
+    assert_eq!(visible(as_tenant, theirs.port, &theirs.lease.service_name).await, [true; 3]);
+    assert_eq!(visible(as_tenant, ours.port, &ours.lease.service_name).await, [false; 3]);
+    assert_eq!(visible(as_socket_admin, ours.port, &ours.lease.service_name).await, [true; 3]);
+    assert_eq!(visible(as_socket_admin, theirs.port, &theirs.lease.service_name).await, [true; 3]);
+
+    // Asking for the other namespace, or touching its leases, is refused both ways
+    assert_eq!(as_anonymous("/history?namespace=integration-tenant").send().await.unwrap().status(), 403);
+    assert_eq!(as_tenant("/lookup?service=integration-isolation-anonymous&namespace=@tcp").send().await.unwrap().status(), 403);
+    for (client, port) in [(&anonymous, theirs.port), (&tenant, ours.port)] {
+        let resp = client.post(format!("{}/v1/release", BASE_URL))
+            .json(&ReleaseRequest { port: Some(port), group_id: None })
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 403);
+    }
+
+    for (client, port) in [(&anonymous, ours.port), (&tenant, theirs.port)] {
+        let resp = client.post(format!("{}/v1/release", BASE_URL))
+            .json(&ReleaseRequest { port: Some(port), group_id: None })
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.status().is_success());
+    }
+}
+
+#[tokio::test]
+async fn test_token_scopes_and_revocation() {
+    let client = Client::new();
+    let Some(admin) = admin_client().await else {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    };
+
+    // Tokens are admin business
+    let resp = client.get(format!("{}/v1/tokens", BASE_URL)).send().await.unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let resp = admin.post(format!("{}/v1/tokens", BASE_URL))
+        .json(&CreateTokenRequest {
+            name: "integration-allocate".to_string(),
+            scope: TokenScope::Allocate,
+            namespace: Some("integration-tokens".to_string()),
+        })
+        .send()
+        .await
+        .expect("Failed to send token request");
+
+    if !resp.status().is_success() {
+        println!("Daemon might not be running. Skipping integration test assertions.");
+        return;
+    }
+
+    let created: CreatedToken = resp.json().await.unwrap();
+    assert_eq!(created.token.namespace, "integration-tokens");
+    let bearer = format!("Bearer {}", created.secret);
+
+    // An allocate token can lease ports, in its own namespace
+    let alloc: AllocateResponse = client.post(format!("{}/v1/alloc", BASE_URL))
+        .header("Authorization", &bearer)
+        .json(&AllocateRequest { service_name: "integration-token-service".to_string(), ..Default::default() })
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(alloc.lease.namespace, "integration-tokens");
+
+    // ...but not manage pools
+    let resp = client.put(format!("{}/v1/pools/integration-token-pool", BASE_URL))
+        .header("Authorization", &bearer)
+        .json(&PoolSpec { min_port: 15100, max_port: 15109, default_ttl: None, strategy: AllocationStrategy::FirstFree })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+    let err: ApiError = resp.json().await.unwrap();
+    assert_eq!(err.code, ErrorCode::Forbidden);
+
+    let resp = client.get(format!("{}/v1/list", BASE_URL))
+        .header("Authorization", "Bearer pm_not-a-real-token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+
+    let resp = admin.delete(format!("{}/v1/tokens/{}", BASE_URL, created.token.id))
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+
+    let resp = client.post(format!("{}/v1/release", BASE_URL))
+        .header("Authorization", &bearer)
+        .json(&ReleaseRequest { port: Some(alloc.port), group_id: None })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+
+    admin.post(format!("{}/v1/release", BASE_URL))
+        .json(&ReleaseRequest { port: Some(alloc.port), group_id: None })
+        .send()
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_sdk_lease_guard_heartbeats_and_releases_on_drop() {
+    let client = PortManagerClient::new(BASE_URL).unwrap();
+    let service_name = "integration-sdk-service";
+
+    let request = AllocateRequest { ttl_seconds: Some(3), ..AllocateRequest::new(service_name) };
+    let guard = match client.lease(request).await {
+        Ok(guard) => guard,
+        Err(e) => {
+            println!("Daemon might not be running ({}). Skipping integration test assertions.", e);
+            return;
+        }
+    };
+    let port = guard.port();
+    let allocated = guard.lease().last_heartbeat;
+
+    // With a TTL of 3s the guard heartbeats every second
+    sleep(Duration::from_millis(2500)).await;
+    let lookup = client.lookup(service_name).await.unwrap();
+    assert_eq!(lookup.port, Some(port));
+    assert!(lookup.lease.unwrap().last_heartbeat > allocated);
+
+    drop(guard);
+    let lookup = client.lookup(service_name).await.unwrap();
+    assert_eq!(lookup.port, None);
+
+    let err = client.allocate(&AllocateRequest::new("not a valid name")).await.unwrap_err();
+    assert_eq!(err.code(), Some(ErrorCode::InvalidServiceName));
+}
+
+#[test]
+fn test_blocking_client_lease_guard() {
+    let client = portmanager::blocking::PortManagerClient::new(BASE_URL).unwrap();
+    let service_name = "integration-blocking-service";
+
+    let request = AllocateRequest { ttl_seconds: Some(3), ..AllocateRequest::new(service_name) };
+    let guard = match client.lease(request) {
+        Ok(guard) => guard,
+        Err(e) => {
+            println!("Daemon might not be running ({}). Skipping integration test assertions.", e);
+            return;
+        }
+    };
+    let port = guard.port();
+    let allocated = guard.lease().last_heartbeat;
+
+    std::thread::sleep(Duration::from_millis(2500));
+    let lookup = client.lookup(service_name).unwrap();
+    assert_eq!(lookup.port, Some(port));
+    assert!(lookup.lease.unwrap().last_heartbeat > allocated);
+
+    drop(guard);
+    assert_eq!(client.lookup(service_name).unwrap().port, None);
+
+    // Groups are released as a whole
+    let roles = vec!["http".to_string(), "grpc".to_string()];
+    let group = client
+        .lease(AllocateRequest { roles: Some(roles), ..AllocateRequest::new("integration-blocking-group") })
+        .unwrap();
+    assert!(group.role("grpc").is_some());
+    group.release().unwrap();
+    assert_eq!(client.lookup("integration-blocking-group").unwrap().port, None);
+}
@@ -0,0 +1,232 @@
+//! Blocking client, for build scripts and synchronous tests.
+//!
+//! Same operations and lease guard as the async client, without an async
+//! runtime. It only talks TCP, so it ignores the daemon's Unix socket.
+//!
+//! ```no_run
+//! use portmanager::blocking::PortManagerClient;
+//! use portmanager::AllocateRequest;
+//!
+//! # fn example() -> portmanager::Result<()> {
+//! let client = PortManagerClient::from_env()?;
+//! let guard = client.lease(AllocateRequest::new("build-fixture"))?;
+//! println!("cargo:rustc-env=FIXTURE_PORT={}", guard.port());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::config::ClientBuilder;
+use crate::error::{Error, Result};
+use common::{
+    AllocateRequest, AllocateResponse, ApiError, ErrorCode, HeartbeatRequest, Lease, LookupResponse, ReleaseRequest,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+impl ClientBuilder {
+    pub fn build_blocking(self) -> Result<PortManagerClient> {
+        if let Some(path) = &self.socket {
+            return Err(Error::Config(format!(
+                "cannot use {}: the blocking client only talks TCP; set a URL instead",
+                path.display()
+            )));
+        }
+        let mut agent = ureq::AgentBuilder::new();
+        if let Some(timeout) = self.timeout {
+            agent = agent.timeout(timeout);
+        }
+        Ok(PortManagerClient {
+            agent: agent.build(),
+            base: self.api_base()?,
+            authorization: self.authorization()?,
+        })
+    }
+}
+
+/// Typed blocking client for the daemon's `/v1` API. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct PortManagerClient {
+    agent: ureq::Agent,
+    base: String,
+    authorization: Option<String>,
+}
+
+impl PortManagerClient {
+    /// Client for the daemon at `url`, e.g. `http://localhost:3030`.
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        ClientBuilder::default().url(url).build_blocking()
+    }
+
+    /// Client configured the way `portctl` is, except that it never uses the
+    /// Unix socket; see [`ClientBuilder::from_env`].
+    pub fn from_env() -> Result<Self> {
+        ClientBuilder::from_env_without_socket()?.build_blocking()
+    }
+
+    pub fn allocate(&self, request: &AllocateRequest) -> Result<AllocateResponse> {
+        self.post("alloc", request)?.into_json().map_err(transport)
+    }
+
+    /// Allocate and hold the lease until the returned guard is dropped.
+    pub fn lease(&self, request: AllocateRequest) -> Result<LeaseGuard> {
+        let response = self.allocate(&request)?;
+        Ok(LeaseGuard::new(self.clone(), response))
+    }
+
+    pub fn release(&self, port: u16) -> Result<()> {
+        self.post("release", &ReleaseRequest { port: Some(port), group_id: None }).map(drop)
+    }
+
+    /// Release every port of a group.
+    pub fn release_group(&self, group_id: &str) -> Result<()> {
+        self.post("release", &ReleaseRequest { port: None, group_id: Some(group_id.to_string()) }).map(drop)
+    }
+
+    /// Renew the TTL of a lease.
+    pub fn heartbeat(&self, port: u16) -> Result<()> {
+        self.post("heartbeat", &HeartbeatRequest { port: Some(port), group_id: None }).map(drop)
+    }
+
+    /// Renew the TTL of every lease in a group.
+    pub fn heartbeat_group(&self, group_id: &str) -> Result<()> {
+        self.post("heartbeat", &HeartbeatRequest { port: None, group_id: Some(group_id.to_string()) }).map(drop)
+    }
+
+    /// Active leases the caller may see.
+    pub fn list(&self) -> Result<Vec<Lease>> {
+        self.get("list", &[])
+    }
+
+    /// Ports leased by a service; `port` is `None` if it holds none.
+    pub fn lookup(&self, service_name: &str) -> Result<LookupResponse> {
+        self.get("lookup", &[("service", service_name)])
+    }
+
+    /// Base URL of the API, e.g. `http://localhost:3030/v1`.
+    pub fn base_url(&self) -> &str {
+        &self.base
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}/{}", self.base, path));
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T> {
+        let request = query.iter().fold(self.request("GET", path), |r, (name, value)| r.query(name, value));
+        check(request.call())?.into_json().map_err(transport)
+    }
+
+    fn post(&self, path: &str, body: &impl Serialize) -> Result<ureq::Response> {
+        check(self.request("POST", path).send_json(body))
+    }
+}
+
+/// Turn error responses into [`Error::Api`].
+fn check(result: std::result::Result<ureq::Response, ureq::Error>) -> Result<ureq::Response> {
+    match result {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(status, response)) => Err(Error::Api(
+            response
+                .into_json::<ApiError>()
+                .unwrap_or_else(|_| ApiError::new(ErrorCode::Internal, format!("HTTP {}", status))),
+        )),
+        Err(e) => Err(Error::Transport(Box::new(e))),
+    }
+}
+
+fn transport(error: std::io::Error) -> Error {
+    Error::Transport(Box::new(error.into()))
+}
+
+/// A lease that is held for as long as the guard lives.
+///
+/// A background thread sends a heartbeat every third of the lease's TTL; the
+/// lease, or its whole group, is released when the guard is dropped. Use
+/// [`LeaseGuard::release`] to find out whether the release succeeded.
+#[derive(Debug)]
+pub struct LeaseGuard {
+    client: PortManagerClient,
+    response: AllocateResponse,
+    /// Dropping it stops the heartbeat thread.
+    stop: Option<Sender<()>>,
+}
+
+impl LeaseGuard {
+    fn new(client: PortManagerClient, response: AllocateResponse) -> Self {
+        let interval = Duration::from_secs((response.lease.ttl_seconds / 3).max(1));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let heartbeat = client.clone();
+        let group_id = response.group_id.clone();
+        let port = response.port;
+        std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let result = match &group_id {
+                    Some(group_id) => heartbeat.heartbeat_group(group_id),
+                    None => heartbeat.heartbeat(port),
+                };
+                match result {
+                    // Released or expired behind our back; there is nothing left to keep alive
+                    Err(Error::Api(e)) if matches!(e.code, ErrorCode::PortNotLeased | ErrorCode::GroupNotFound) => return,
+                    // Anything else may pass, e.g. a daemon restart, and the TTL allows a few misses
+                    _ => {}
+                }
+            }
+        });
+        LeaseGuard { client, response, stop: Some(stop) }
+    }
+
+    /// The leased port; for a group, its first port.
+    pub fn port(&self) -> u16 {
+        self.response.port
+    }
+
+    /// Port of a named role, for groups allocated with `roles`.
+    pub fn role(&self, role: &str) -> Option<u16> {
+        self.leases().iter().find(|l| l.role.as_deref() == Some(role)).map(|l| l.port)
+    }
+
+    pub fn lease(&self) -> &Lease {
+        &self.response.lease
+    }
+
+    /// Every lease held by the guard; more than one for a group.
+    pub fn leases(&self) -> &[Lease] {
+        if self.response.leases.is_empty() {
+            std::slice::from_ref(&self.response.lease)
+        } else {
+            &self.response.leases
+        }
+    }
+
+    pub fn group_id(&self) -> Option<&str> {
+        self.response.group_id.as_deref()
+    }
+
+    /// Release the lease now, reporting failure instead of ignoring it like drop does.
+    pub fn release(mut self) -> Result<()> {
+        self.release_now()
+    }
+
+    fn release_now(&mut self) -> Result<()> {
+        // Only the first call gets past this, whether from `release` or drop
+        if self.stop.take().is_none() {
+            return Ok(());
+        }
+        match &self.response.group_id {
+            Some(group_id) => self.client.release_group(group_id),
+            None => self.client.release(self.response.port),
+        }
+    }
+}
+
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        let _ = self.release_now();
+    }
+}
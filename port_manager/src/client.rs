@@ -0,0 +1,270 @@
+use crate::config::ClientBuilder;
+use crate::error::{Error, Result};
+use crate::guard::LeaseGuard;
+use common::{
+    AllocateRequest, AllocateResponse, ApiError, ApiToken, Conflict, ConflictsParams, CreateTokenRequest, CreatedToken,
+    ErrorCode, HeartbeatRequest, HistoryEntry, HistoryQuery, ImportMode, ImportParams, ImportReport, Lease, LeaseEvent,
+    LookupResponse, Pool, PoolSpec, ReleaseRequest, Reservation, ReserveRequest, StateExport,
+};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{RequestBuilder, Response};
+use std::time::Duration;
+
+impl ClientBuilder {
+    pub fn build(self) -> Result<PortManagerClient> {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = self.authorization()? {
+            let value = HeaderValue::from_str(&authorization)
+                .map_err(|_| Error::Config("API token contains invalid characters".to_string()))?;
+            headers.insert(AUTHORIZATION, value);
+        }
+        let mut http = reqwest::Client::builder().default_headers(headers);
+        if let Some(timeout) = self.timeout {
+            http = http.timeout(timeout);
+        }
+
+        if let Some(path) = &self.socket {
+            #[cfg(not(unix))]
+            return Err(Error::Config(format!("cannot use {}: no Unix sockets on this platform", path.display())));
+            #[cfg(unix)]
+            {
+                http = http.unix_socket(path.clone());
+            }
+        }
+        // On the socket the host is ignored, but requests still need one
+        let base = self.api_base()?;
+
+        Ok(PortManagerClient {
+            http: http.build()?,
+            base,
+            builder: self,
+        })
+    }
+}
+
+/// Typed client for the daemon's `/v1` API. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct PortManagerClient {
+    http: reqwest::Client,
+    base: String,
+    /// Kept so a `LeaseGuard` can make a client of its own when dropped.
+    builder: ClientBuilder,
+}
+
+impl PortManagerClient {
+    /// Client for the daemon at `url`, e.g. `http://localhost:3030`.
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        ClientBuilder::default().url(url).build()
+    }
+
+    /// Client configured the way `portctl` is; see [`ClientBuilder::from_env`].
+    pub fn from_env() -> Result<Self> {
+        ClientBuilder::from_env()?.build()
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    pub async fn allocate(&self, request: &AllocateRequest) -> Result<AllocateResponse> {
+        let response = self.send(self.http.post(self.url("alloc")).json(request)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Allocate and hold the lease until the returned guard is dropped.
+    pub async fn lease(&self, request: AllocateRequest) -> Result<LeaseGuard> {
+        let response = self.allocate(&request).await?;
+        Ok(LeaseGuard::new(self.clone(), response))
+    }
+
+    pub async fn release(&self, port: u16) -> Result<()> {
+        self.release_lease(&ReleaseRequest { port: Some(port), group_id: None }).await
+    }
+
+    /// Release every port of a group.
+    pub async fn release_group(&self, group_id: &str) -> Result<()> {
+        self.release_lease(&ReleaseRequest { port: None, group_id: Some(group_id.to_string()) }).await
+    }
+
+    /// Renew the TTL of a lease.
+    pub async fn heartbeat(&self, port: u16) -> Result<()> {
+        self.heartbeat_lease(&HeartbeatRequest { port: Some(port), group_id: None }).await
+    }
+
+    /// Renew the TTL of every lease in a group.
+    pub async fn heartbeat_group(&self, group_id: &str) -> Result<()> {
+        self.heartbeat_lease(&HeartbeatRequest { port: None, group_id: Some(group_id.to_string()) }).await
+    }
+
+    /// Active leases the caller may see.
+    pub async fn list(&self) -> Result<Vec<Lease>> {
+        self.list_filtered(None, None).await
+    }
+
+    /// Active leases the caller may see, limited to a pool and/or namespace.
+    /// Only admins may ask for another namespace than their own.
+    pub async fn list_filtered(&self, pool: Option<&str>, namespace: Option<&str>) -> Result<Vec<Lease>> {
+        let mut request = self.http.get(self.url("list"));
+        if let Some(pool) = pool {
+            request = request.query(&[("pool", pool)]);
+        }
+        if let Some(namespace) = namespace {
+            request = request.query(&[("namespace", namespace)]);
+        }
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    /// Ports leased by a service; `port` is `None` if it holds none.
+    pub async fn lookup(&self, service_name: &str) -> Result<LookupResponse> {
+        let response = self.send(self.http.get(self.url("lookup")).query(&[("service", service_name)])).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Lease allocations, releases and expiries, newest first.
+    pub async fn history(&self, query: &HistoryQuery) -> Result<Vec<HistoryEntry>> {
+        Ok(self.send(self.http.get(self.url("history")).query(query)).await?.json().await?)
+    }
+
+    /// Follow lease changes as they happen.
+    pub async fn events(&self) -> Result<EventStream> {
+        let response = self.send(self.http.get(self.url("events"))).await?;
+        Ok(EventStream { response, buffer: Vec::new() })
+    }
+
+    /// Processes listening in a pool's range without a lease, from the daemon's
+    /// latest periodic scan, or from a new scan with `refresh`.
+    pub async fn conflicts(&self, refresh: bool) -> Result<Vec<Conflict>> {
+        let request = self.http.get(self.url("conflicts")).query(&ConflictsParams { refresh });
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    pub async fn pools(&self) -> Result<Vec<Pool>> {
+        Ok(self.send(self.http.get(self.url("pools"))).await?.json().await?)
+    }
+
+    /// Create or update a named pool. Needs admin rights.
+    pub async fn save_pool(&self, name: &str, spec: &PoolSpec) -> Result<Pool> {
+        let request = self.http.put(self.url(&format!("pools/{}", name))).json(spec);
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    /// Needs admin rights; fails while the pool has active leases.
+    pub async fn delete_pool(&self, name: &str) -> Result<()> {
+        self.send(self.http.delete(self.url(&format!("pools/{}", name)))).await?;
+        Ok(())
+    }
+
+    pub async fn reservations(&self) -> Result<Vec<Reservation>> {
+        Ok(self.send(self.http.get(self.url("reservations"))).await?.json().await?)
+    }
+
+    /// Keep a port for one service permanently. Needs admin rights.
+    pub async fn reserve(&self, request: &ReserveRequest) -> Result<Reservation> {
+        Ok(self.send(self.http.post(self.url("reservations")).json(request)).await?.json().await?)
+    }
+
+    /// Needs admin rights.
+    pub async fn unreserve(&self, port: u16) -> Result<()> {
+        self.send(self.http.delete(self.url(&format!("reservations/{}", port)))).await?;
+        Ok(())
+    }
+
+    /// Needs admin rights.
+    pub async fn tokens(&self) -> Result<Vec<ApiToken>> {
+        Ok(self.send(self.http.get(self.url("tokens"))).await?.json().await?)
+    }
+
+    /// Needs admin rights. The secret is only ever returned here.
+    pub async fn create_token(&self, request: &CreateTokenRequest) -> Result<CreatedToken> {
+        Ok(self.send(self.http.post(self.url("tokens")).json(request)).await?.json().await?)
+    }
+
+    /// Needs admin rights.
+    pub async fn revoke_token(&self, id: &str) -> Result<()> {
+        self.send(self.http.delete(self.url(&format!("tokens/{}", id)))).await?;
+        Ok(())
+    }
+
+    /// Leases, reservations and sticky ports. Needs admin rights.
+    pub async fn export(&self) -> Result<StateExport> {
+        Ok(self.send(self.http.get(self.url("export"))).await?.json().await?)
+    }
+
+    /// Restore an export. Needs admin rights.
+    pub async fn import(&self, export: &StateExport, mode: ImportMode) -> Result<ImportReport> {
+        let request = self.http.post(self.url("import")).query(&ImportParams { mode }).json(export);
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    /// The underlying HTTP client, for endpoints without a method here.
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Base URL of the API, e.g. `http://localhost:3030/v1`.
+    pub fn base_url(&self) -> &str {
+        &self.base
+    }
+
+    pub(crate) async fn release_lease(&self, request: &ReleaseRequest) -> Result<()> {
+        self.send(self.http.post(self.url("release")).json(request)).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn heartbeat_lease(&self, request: &HeartbeatRequest) -> Result<()> {
+        self.send(self.http.post(self.url("heartbeat")).json(request)).await?;
+        Ok(())
+    }
+
+    /// A client with the same settings but its own connection pool, and a timeout.
+    pub(crate) fn detached(&self, timeout: Duration) -> Result<Self> {
+        self.builder.clone().timeout(timeout).build()
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base, path)
+    }
+
+    /// Send `request`, turning error responses into [`Error::Api`].
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        Err(Error::Api(
+            response
+                .json::<ApiError>()
+                .await
+                .unwrap_or_else(|_| ApiError::new(ErrorCode::Internal, status.to_string())),
+        ))
+    }
+}
+
+/// Lease changes from [`PortManagerClient::events`], as the daemon sends them.
+#[derive(Debug)]
+pub struct EventStream {
+    response: Response,
+    buffer: Vec<u8>,
+}
+
+impl EventStream {
+    /// The next event, or `None` once the daemon closes the stream.
+    pub async fn next(&mut self) -> Result<Option<LeaseEvent>> {
+        loop {
+            // Server-sent events are separated by a blank line; skip keep-alives and unknown kinds
+            while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+                let frame: Vec<u8> = self.buffer.drain(..end + 2).collect();
+                let frame = String::from_utf8_lossy(&frame);
+                let data = frame.lines().find_map(|l| l.strip_prefix("data:"));
+                if let Some(event) = data.and_then(|d| serde_json::from_str(d.trim()).ok()) {
+                    return Ok(Some(event));
+                }
+            }
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Where the daemon listens unless configured otherwise.
+pub const DEFAULT_URL: &str = "http://localhost:3030";
+
+/// Settings read from `~/.portmanager/client.toml`.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ClientConfig {
+    /// Daemon URL used when neither `--url` nor `PORTMANAGER_URL` is given.
+    url: Option<String>,
+}
+
+fn read_client_config() -> Result<ClientConfig> {
+    let Some(home) = std::env::var_os("HOME") else {
+        return Ok(ClientConfig::default());
+    };
+    let path = PathBuf::from(home).join(".portmanager").join("client.toml");
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)
+            .map_err(|e| Error::Config(format!("invalid config {}: {}", path.display(), e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ClientConfig::default()),
+        Err(e) => Err(Error::Config(format!("failed to read {}: {}", path.display(), e))),
+    }
+}
+
+/// Configures a client. Without a URL or socket it talks to [`DEFAULT_URL`].
+#[derive(Clone, Default)]
+pub struct ClientBuilder {
+    pub(crate) url: Option<String>,
+    pub(crate) socket: Option<PathBuf>,
+    pub(crate) token: Option<String>,
+    pub(crate) timeout: Option<Duration>,
+}
+
+impl ClientBuilder {
+    /// Endpoint and token found the way `portctl` finds them.
+    ///
+    /// The daemon is the one at `PORTMANAGER_URL`, or at `url` in
+    /// `~/.portmanager/client.toml`. Without either, the daemon's Unix socket
+    /// is used if it accepts connections (`PORTMANAGER_SOCKET` overrides its
+    /// path; set it empty to skip the socket). `PORTMANAGER_TOKEN`, if set, is
+    /// sent as the API token.
+    pub fn from_env() -> Result<Self> {
+        let builder = Self::from_env_without_socket()?;
+        if builder.url.is_some() {
+            return Ok(builder);
+        }
+
+        #[cfg(unix)]
+        {
+            let path = std::env::var_os("PORTMANAGER_SOCKET")
+                .map(PathBuf::from)
+                .unwrap_or_else(common::default_socket_path);
+            if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                return Ok(builder.socket(path));
+            }
+        }
+        Ok(builder)
+    }
+
+    /// Like `from_env`, for clients that only speak TCP.
+    pub(crate) fn from_env_without_socket() -> Result<Self> {
+        let url = match std::env::var("PORTMANAGER_URL").ok().filter(|u| !u.is_empty()) {
+            Some(url) => Some(url),
+            None => read_client_config()?.url,
+        };
+        Ok(ClientBuilder {
+            url,
+            token: std::env::var("PORTMANAGER_TOKEN").ok().filter(|t| !t.is_empty()),
+            ..Default::default()
+        })
+    }
+
+    /// Talk to the daemon at `url`, e.g. `http://192.168.1.10:3030`.
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self.socket = None;
+        self
+    }
+
+    /// Talk to the daemon over its Unix socket, which lets it tell who is calling.
+    /// Only the async client supports this.
+    pub fn socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.socket = Some(path.into());
+        self.url = None;
+        self
+    }
+
+    /// API token to authenticate with.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Fail requests that take longer than `timeout`. There is no limit by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Value of the `Authorization` header, if a token is set.
+    #[cfg(any(feature = "async", feature = "blocking"))]
+    pub(crate) fn authorization(&self) -> Result<Option<String>> {
+        match &self.token {
+            Some(token) if token.chars().any(|c| c.is_control() || c.is_whitespace()) => {
+                Err(Error::Config("API token contains invalid characters".to_string()))
+            }
+            Some(token) => Ok(Some(format!("Bearer {}", token))),
+            None => Ok(None),
+        }
+    }
+
+    /// Base URL of the API at the configured URL, or at [`DEFAULT_URL`].
+    #[cfg(any(feature = "async", feature = "blocking"))]
+    pub(crate) fn api_base(&self) -> Result<String> {
+        let url = self.url.as_deref().unwrap_or(DEFAULT_URL);
+        match url.strip_prefix("http://").or_else(|| url.strip_prefix("https://")) {
+            Some(host) if !host.is_empty() && !host.starts_with('/') => {
+                Ok(format!("{}/v1", url.trim_end_matches('/')))
+            }
+            _ => Err(Error::Config(format!("invalid daemon URL: {} (expected e.g. http://192.168.1.10:3030)", url))),
+        }
+    }
+}
+
+impl fmt::Debug for ClientBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientBuilder")
+            .field("url", &self.url)
+            .field("socket", &self.socket)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
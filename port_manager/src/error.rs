@@ -0,0 +1,68 @@
+use common::{ApiError, ErrorCode};
+use std::fmt;
+
+/// Why a call to the daemon failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The daemon rejected the request.
+    Api(ApiError),
+    /// The daemon could not be reached, or its response could not be read.
+    #[cfg(feature = "async")]
+    Http(reqwest::Error),
+    /// Like `Http`, from the blocking client.
+    #[cfg(feature = "blocking")]
+    Transport(Box<ureq::Error>),
+    /// The client is misconfigured, e.g. an invalid URL or config file.
+    Config(String),
+}
+
+impl Error {
+    /// Error code sent by the daemon, if it got that far.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::Api(e) => Some(e.code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Api(e) => write!(f, "{}", e),
+            #[cfg(feature = "async")]
+            Error::Http(e) => write!(f, "cannot reach the daemon: {}", e),
+            #[cfg(feature = "blocking")]
+            Error::Transport(e) => write!(f, "cannot reach the daemon: {}", e),
+            Error::Config(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Api(e) => Some(e),
+            #[cfg(feature = "async")]
+            Error::Http(e) => Some(e),
+            #[cfg(feature = "blocking")]
+            Error::Transport(e) => Some(e),
+            Error::Config(_) => None,
+        }
+    }
+}
+
+impl From<ApiError> for Error {
+    fn from(error: ApiError) -> Self {
+        Error::Api(error)
+    }
+}
+
+#[cfg(feature = "async")]
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Http(error)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,114 @@
+use crate::client::PortManagerClient;
+use crate::error::{Error, Result};
+use common::{AllocateResponse, ErrorCode, HeartbeatRequest, Lease, ReleaseRequest};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// How long dropping a guard waits for the daemon to confirm the release.
+const DROP_RELEASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A lease that is held for as long as the guard lives.
+///
+/// The guard sends a heartbeat every third of the lease's TTL and releases
+/// the lease, or its whole group, when dropped. Dropping blocks until the
+/// daemon answers, so the port is free again by the time the next statement
+/// runs, even at the end of a `#[tokio::test]`. Use [`LeaseGuard::release`]
+/// to find out whether the release succeeded.
+#[derive(Debug)]
+pub struct LeaseGuard {
+    client: PortManagerClient,
+    response: AllocateResponse,
+    heartbeat: JoinHandle<()>,
+    released: bool,
+}
+
+impl LeaseGuard {
+    pub(crate) fn new(client: PortManagerClient, response: AllocateResponse) -> Self {
+        let interval = Duration::from_secs((response.lease.ttl_seconds / 3).max(1));
+        let request = match &response.group_id {
+            Some(group_id) => HeartbeatRequest { port: None, group_id: Some(group_id.clone()) },
+            None => HeartbeatRequest { port: Some(response.port), group_id: None },
+        };
+        let heartbeat = tokio::spawn(keep_alive(client.clone(), request, interval));
+        LeaseGuard { client, response, heartbeat, released: false }
+    }
+
+    /// The leased port; for a group, its first port.
+    pub fn port(&self) -> u16 {
+        self.response.port
+    }
+
+    /// Port of a named role, for groups allocated with `roles`.
+    pub fn role(&self, role: &str) -> Option<u16> {
+        self.leases().iter().find(|l| l.role.as_deref() == Some(role)).map(|l| l.port)
+    }
+
+    pub fn lease(&self) -> &Lease {
+        &self.response.lease
+    }
+
+    /// Every lease held by the guard; more than one for a group.
+    pub fn leases(&self) -> &[Lease] {
+        if self.response.leases.is_empty() {
+            std::slice::from_ref(&self.response.lease)
+        } else {
+            &self.response.leases
+        }
+    }
+
+    pub fn group_id(&self) -> Option<&str> {
+        self.response.group_id.as_deref()
+    }
+
+    /// Release the lease now, reporting failure instead of ignoring it like drop does.
+    pub async fn release(mut self) -> Result<()> {
+        self.heartbeat.abort();
+        self.released = true;
+        self.client.release_lease(&release_request(&self.response)).await
+    }
+}
+
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+        if self.released {
+            return;
+        }
+
+        // Drop cannot await, and a task spawned on the caller's runtime never runs
+        // if that runtime is shutting down. Release from a runtime of our own, with
+        // a client of its own, since pooled connections belong to the caller's runtime.
+        let Ok(client) = self.client.detached(DROP_RELEASE_TIMEOUT) else {
+            return;
+        };
+        let request = release_request(&self.response);
+        let _ = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().ok()?;
+            runtime.block_on(client.release_lease(&request)).ok()
+        })
+        .join();
+    }
+}
+
+fn release_request(response: &AllocateResponse) -> ReleaseRequest {
+    match &response.group_id {
+        Some(group_id) => ReleaseRequest { port: None, group_id: Some(group_id.clone()) },
+        None => ReleaseRequest { port: Some(response.port), group_id: None },
+    }
+}
+
+async fn keep_alive(client: PortManagerClient, request: HeartbeatRequest, interval: Duration) {
+    let mut ticker = time::interval(interval);
+    // The first tick is immediate, and the lease was only just allocated
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        match client.heartbeat_lease(&request).await {
+            // Released or expired behind our back; there is nothing left to keep alive
+            Err(Error::Api(e)) if matches!(e.code, ErrorCode::PortNotLeased | ErrorCode::GroupNotFound) => return,
+            // Anything else may pass, e.g. a daemon restart, and the TTL allows a few misses
+            _ => {}
+        }
+    }
+}
@@ -1,14 +1,45 @@
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
+//! Client SDK for the PortManager daemon.
+//!
+//! [`PortManagerClient`] wraps the daemon's HTTP API with typed requests and
+//! errors. [`LeaseGuard`] holds a lease for as long as it lives: it sends
+//! heartbeats in the background and releases the port when dropped.
+//!
+//! Both need Tokio. With the `blocking` feature, [`blocking`] offers the same
+//! for build scripts and synchronous tests; disable the default `async`
+//! feature to leave Tokio out entirely.
+//!
+//! ```no_run
+//! use portmanager::{AllocateRequest, PortManagerClient};
+//!
+//! # async fn example() -> portmanager::Result<()> {
+//! // Same endpoint as `portctl`: PORTMANAGER_URL, ~/.portmanager/client.toml,
+//! // the local socket, or http://localhost:3030
+//! let client = PortManagerClient::from_env()?;
+//!
+//! let guard = client.lease(AllocateRequest::new("my-api")).await?;
+//! println!("serving on port {}", guard.port());
+//! // ... the port stays leased until `guard` is dropped
+//! guard.release().await?;
+//! # Ok(())
+//! # }
+//! ```
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "async")]
+mod client;
+mod config;
+mod error;
+#[cfg(feature = "async")]
+mod guard;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+#[cfg(feature = "async")]
+pub use client::{EventStream, PortManagerClient};
+pub use config::{ClientBuilder, DEFAULT_URL};
+pub use error::{Error, Result};
+#[cfg(feature = "async")]
+pub use guard::LeaseGuard;
+
+pub use common::{
+    AllocateRequest, AllocateResponse, AllocationStrategy, ApiError, ErrorCode, Lease, LookupResponse, SkippedPort,
+};